@@ -0,0 +1,134 @@
+use std::{
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use crossbeam::queue::ArrayQueue;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How many free buffers are kept around for reuse.
+const MAX_FREE: usize = 1024;
+/// Larger buffers are freed rather than kept, so a burst of large messages
+/// doesn't pin down memory for good.
+const MAX_REUSED_CAPACITY: usize = 64 * 1024;
+
+/// Recycles the buffers that requests and responses are read into.
+///
+/// A buffer is only taken once there is a message to read into it and is
+/// handed out as `Bytes`, which moves between the downstream client and the
+/// upstream connection without copying or locking. It comes back to the pool
+/// once the last reference to it is gone. Idle connections don't hold on to
+/// any buffers.
+///
+/// The free buffers are kept in a lock-free queue, so the clients and the
+/// upstream connections don't contend on a lock for every message.
+pub struct BufferPool {
+    free: ArrayQueue<BytesMut>,
+    allocated: AtomicUsize,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        BufferPool {
+            free: ArrayQueue::new(MAX_FREE),
+            allocated: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reads a message of `len` bytes from `reader` into a buffer. The bytes
+    /// are read straight into its spare capacity, without zeroing it first.
+    pub async fn read<R>(&self, reader: &mut R, len: usize) -> io::Result<BytesMut>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = self.free.pop().unwrap_or_else(|| {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            BytesMut::new()
+        });
+        buf.reserve(len);
+
+        let mut rest = (&mut buf).limit(len);
+        while rest.has_remaining_mut() {
+            if reader.read_buf(&mut rest).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        Ok(buf)
+    }
+
+    /// Gives a buffer back. It's only reused if nothing else refers to it.
+    pub fn put(&self, buf: Bytes) {
+        let Ok(mut buf) = buf.try_into_mut() else {
+            return;
+        };
+
+        if buf.capacity() > MAX_REUSED_CAPACITY {
+            return;
+        }
+
+        buf.clear();
+        // Dropped if there are enough free buffers already
+        let _ = self.free.push(buf);
+    }
+
+    /// The number of buffers that are ready to be reused.
+    pub fn free(&self) -> usize {
+        self.free.len()
+    }
+
+    /// The number of buffers that had to be allocated because there was no
+    /// free one to reuse.
+    pub fn allocated(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::BufferPool;
+
+    #[tokio::test]
+    async fn buffers_are_reused_once_nothing_refers_to_them() -> io::Result<()> {
+        let pool = BufferPool::new();
+        let buf = pool.read(&mut &b"abcd"[..], 4).await?.freeze();
+
+        let shared = buf.clone();
+        pool.put(buf);
+        assert_eq!(0, pool.free());
+
+        pool.put(shared);
+        assert_eq!(1, pool.free());
+
+        // The old contents don't leak into the next message
+        let mut reader = &b"ef"[..];
+        assert_eq!(&b"ef"[..], &pool.read(&mut reader, 2).await?[..]);
+        assert_eq!(0, pool.free());
+        assert_eq!(1, pool.allocated());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_the_message_is_read() -> io::Result<()> {
+        let pool = BufferPool::new();
+        let mut reader = &b"abcdef"[..];
+
+        assert_eq!(&b"abcd"[..], &pool.read(&mut reader, 4).await?[..]);
+        assert_eq!(b"ef", reader);
+
+        let err = pool.read(&mut reader, 4).await.unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+
+        Ok(())
+    }
+}
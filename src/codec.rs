@@ -0,0 +1,383 @@
+use std::io;
+
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::frame::{ErrorCode, Frame, FrameError, HEADER_LEN, V1, V2};
+
+/// The longest header any of the built-in codecs reads or writes.
+pub const MAX_HEADER_LEN: usize = HEADER_LEN;
+
+/// Describes how messages are delimited on the wire.
+///
+/// Codecs that can't carry a request id produce v1 frames, which means the
+/// messages on that connection are served one at a time and in order.
+pub trait Codec: Send + Sync {
+    /// The number of bytes that have to be read before calling `decode`.
+    fn header_len(&self) -> usize;
+
+    /// Decodes the header at the start of `buf`. Returns `Ok(None)` if the
+    /// header is longer than `buf`, in which case the caller should read
+    /// one more byte and try again.
+    fn decode(&self, buf: &[u8]) -> Result<Option<Frame>, FrameError>;
+
+    /// Appends the header of `frame` to `dst`.
+    fn encode(&self, frame: &Frame, dst: &mut Vec<u8>);
+
+    /// Appends a complete error response for `request_id` to `dst`. Returns
+    /// false if the codec has no way to tell the client about errors, in
+    /// which case the only option left is to close the connection.
+    fn encode_error(&self, _request_id: Option<u16>, _code: ErrorCode, _dst: &mut Vec<u8>) -> bool {
+        false
+    }
+
+    /// Whether the codec carries request ids.
+    fn multiplexed(&self) -> bool {
+        false
+    }
+
+    /// The longest message the codec can carry.
+    fn max_msg_len(&self) -> usize {
+        u32::MAX as usize
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /// The 8 byte l3 header described in the README.
+    #[default]
+    L3,
+    /// The l3 header with a request id on every message, in both directions.
+    L3V2,
+    /// A 2 byte big endian length prefix.
+    U16Be,
+    /// A 4 byte big endian length prefix.
+    U32Be,
+    /// An unsigned LEB128 (protobuf style) length prefix.
+    Varint,
+}
+
+impl Framing {
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            Framing::L3 => &L3,
+            Framing::L3V2 => &L3V2,
+            Framing::U16Be => &U16Be,
+            Framing::U32Be => &U32Be,
+            Framing::Varint => &Varint,
+        }
+    }
+}
+
+/// Reads the next header from `reader`. The outer error is a stream failure,
+/// the inner one means that the header is malformed.
+pub async fn read_frame<R>(
+    codec: &dyn Codec,
+    reader: &mut R,
+) -> io::Result<Result<Frame, FrameError>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = [0u8; MAX_HEADER_LEN];
+    let mut n = codec.header_len();
+    reader.read_exact(&mut buf[0..n]).await?;
+
+    loop {
+        match codec.decode(&buf[0..n]) {
+            Ok(Some(frame)) => return Ok(Ok(frame)),
+            Err(e) => return Ok(Err(e)),
+            Ok(None) if n == MAX_HEADER_LEN => return Ok(Err(FrameError::LengthOverflow)),
+            Ok(None) => {
+                reader.read_exact(&mut buf[n..n + 1]).await?;
+                n += 1;
+            }
+        }
+    }
+}
+
+fn new_frame(msg_len: u32) -> Result<Option<Frame>, FrameError> {
+    if msg_len == 0 {
+        return Err(FrameError::ZeroMessageLength);
+    }
+
+    Ok(Some(Frame::new(V1, msg_len)))
+}
+
+pub struct L3;
+
+impl Codec for L3 {
+    fn header_len(&self) -> usize {
+        HEADER_LEN
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<Option<Frame>, FrameError> {
+        let header: &[u8; HEADER_LEN] = buf.try_into().expect("l3 headers are 8 bytes");
+        Frame::from_bytes(header).map(Some)
+    }
+
+    fn encode(&self, frame: &Frame, dst: &mut Vec<u8>) {
+        // The load balancer consumes the v1 header, the payload is forwarded as is
+        if frame.request_id().is_some() {
+            dst.extend_from_slice(&frame.as_bytes());
+        }
+    }
+
+    fn encode_error(&self, request_id: Option<u16>, code: ErrorCode, dst: &mut Vec<u8>) -> bool {
+        // v1 responses don't have a header, so there is nothing to set the error flag on
+        let Some(id) = request_id else {
+            return false;
+        };
+
+        dst.extend_from_slice(&Frame::error(id).as_bytes());
+        dst.push(code as u8);
+        true
+    }
+}
+
+/// Only v2 frames, so the other side never has to guess whether a message
+/// starts with a header.
+pub struct L3V2;
+
+impl Codec for L3V2 {
+    fn header_len(&self) -> usize {
+        HEADER_LEN
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<Option<Frame>, FrameError> {
+        let header: &[u8; HEADER_LEN] = buf.try_into().expect("l3 headers are 8 bytes");
+        if header[0] != V2 {
+            let [_, _, p2, p3, len @ ..] = *header;
+            return Err(FrameError::InvalidRequestVersion {
+                version: header[0],
+                request_id: u16::from_le_bytes([p2, p3]),
+                msg_len: u32::from_le_bytes(len),
+            });
+        }
+
+        Frame::from_bytes(header).map(Some)
+    }
+
+    fn encode(&self, frame: &Frame, dst: &mut Vec<u8>) {
+        dst.extend_from_slice(&frame.as_bytes());
+    }
+
+    fn encode_error(&self, request_id: Option<u16>, code: ErrorCode, dst: &mut Vec<u8>) -> bool {
+        L3.encode_error(request_id, code, dst)
+    }
+
+    fn multiplexed(&self) -> bool {
+        true
+    }
+}
+
+pub struct U16Be;
+
+impl Codec for U16Be {
+    fn header_len(&self) -> usize {
+        2
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<Option<Frame>, FrameError> {
+        new_frame(u16::from_be_bytes([buf[0], buf[1]]) as u32)
+    }
+
+    fn encode(&self, frame: &Frame, dst: &mut Vec<u8>) {
+        // Configs with a longer max_msg_len are rejected when they are loaded
+        dst.extend_from_slice(&(frame.msg_len as u16).to_be_bytes());
+    }
+
+    fn max_msg_len(&self) -> usize {
+        u16::MAX as usize
+    }
+}
+
+pub struct U32Be;
+
+impl Codec for U32Be {
+    fn header_len(&self) -> usize {
+        4
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<Option<Frame>, FrameError> {
+        new_frame(u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]))
+    }
+
+    fn encode(&self, frame: &Frame, dst: &mut Vec<u8>) {
+        dst.extend_from_slice(&frame.msg_len.to_be_bytes());
+    }
+}
+
+pub struct Varint;
+
+impl Codec for Varint {
+    fn header_len(&self) -> usize {
+        1
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<Option<Frame>, FrameError> {
+        let mut msg_len: u64 = 0;
+        for (i, b) in buf.iter().enumerate() {
+            msg_len |= ((b & 0x7f) as u64) << (7 * i);
+            if b & 0x80 == 0 {
+                let msg_len = u32::try_from(msg_len).map_err(|_| FrameError::LengthOverflow)?;
+                return new_frame(msg_len);
+            }
+        }
+
+        // A u32 takes at most 5 bytes
+        if buf.len() >= 5 {
+            return Err(FrameError::LengthOverflow);
+        }
+
+        Ok(None)
+    }
+
+    fn encode(&self, frame: &Frame, dst: &mut Vec<u8>) {
+        let mut msg_len = frame.msg_len;
+        while msg_len >= 0x80 {
+            dst.push((msg_len as u8) | 0x80);
+            msg_len >>= 7;
+        }
+        dst.push(msg_len as u8);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::frame::{ErrorCode, Frame, FrameError, V1};
+
+    use super::{read_frame, Framing};
+
+    async fn round_trip(framing: Framing, msg_len: u32) -> io::Result<Frame> {
+        let codec = framing.codec();
+        let mut header = vec![];
+        codec.encode(&Frame::new(V1, msg_len), &mut header);
+
+        let mut reader = header.as_slice();
+        let frame = read_frame(codec, &mut reader)
+            .await?
+            .expect("header should be valid");
+        assert!(reader.is_empty(), "the whole header should be consumed");
+
+        Ok(frame)
+    }
+
+    #[tokio::test]
+    async fn length_prefixed_codecs_round_trip() -> io::Result<()> {
+        for framing in [Framing::U16Be, Framing::U32Be, Framing::Varint] {
+            for msg_len in [1, 127, 128, 300, 65535] {
+                assert_eq!(Frame::new(V1, msg_len), round_trip(framing, msg_len).await?);
+            }
+        }
+
+        assert_eq!(
+            Frame::new(V1, u32::MAX),
+            round_trip(Framing::Varint, u32::MAX).await?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn big_endian_codecs_use_network_byte_order() -> Result<(), FrameError> {
+        let mut header = vec![];
+        Framing::U32Be
+            .codec()
+            .encode(&Frame::new(V1, 0x01020304), &mut header);
+        assert_eq!(vec![0x01, 0x02, 0x03, 0x04], header);
+
+        let frame = Framing::U16Be.codec().decode(&[0x01, 0x02])?;
+        assert_eq!(Some(Frame::new(V1, 0x0102)), frame);
+
+        Ok(())
+    }
+
+    #[test]
+    fn varint_needs_more_bytes_while_the_continuation_bit_is_set() -> Result<(), FrameError> {
+        let codec = Framing::Varint.codec();
+        assert_eq!(None, codec.decode(&[0xac])?);
+        assert_eq!(Some(Frame::new(V1, 300)), codec.decode(&[0xac, 0x02])?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn varint_rejects_lengths_that_overflow_a_u32() {
+        let codec = Framing::Varint.codec();
+        match codec.decode(&[0xff, 0xff, 0xff, 0xff, 0x7f]).unwrap_err() {
+            FrameError::LengthOverflow => {}
+            _ => panic!("invalid error"),
+        }
+    }
+
+    #[test]
+    fn length_prefixed_codecs_reject_zero_length() {
+        match Framing::U16Be.codec().decode(&[0x00, 0x00]).unwrap_err() {
+            FrameError::ZeroMessageLength => {}
+            _ => panic!("invalid error"),
+        }
+    }
+
+    #[test]
+    fn l3_codec_only_writes_the_header_of_v2_frames() {
+        let codec = Framing::L3.codec();
+
+        let mut header = vec![];
+        codec.encode(&Frame::new(V1, 5), &mut header);
+        assert!(header.is_empty());
+
+        let frame = Frame::with_request_id(7, 5);
+        codec.encode(&frame, &mut header);
+        assert_eq!(frame.as_bytes().to_vec(), header);
+    }
+
+    #[test]
+    fn l3_v2_codec_only_carries_v2_frames() {
+        let codec = Framing::L3V2.codec();
+        assert!(codec.multiplexed());
+
+        let frame = Frame::with_request_id(7, 5);
+        let mut header = vec![];
+        codec.encode(&frame, &mut header);
+        assert_eq!(frame.as_bytes().to_vec(), header);
+        assert_eq!(Some(frame), codec.decode(&header).unwrap());
+
+        // The id and the length of other versions can still be read
+        let mut other = Frame::with_request_id(7, 5).as_bytes();
+        other[0] = 3;
+        match codec.decode(&other).unwrap_err() {
+            FrameError::InvalidRequestVersion {
+                version: 3,
+                request_id: 7,
+                msg_len: 5,
+            } => {}
+            e => panic!("invalid error {e:?}"),
+        }
+
+        let v1 = Frame::new(V1, 5).as_bytes();
+        assert!(codec.decode(&v1).is_err());
+    }
+
+    #[test]
+    fn only_v2_requests_of_l3_clients_get_error_frames() {
+        let mut buf = vec![];
+        assert!(!Framing::L3
+            .codec()
+            .encode_error(None, ErrorCode::QueueTimeout, &mut buf));
+        assert!(!Framing::U32Be
+            .codec()
+            .encode_error(Some(7), ErrorCode::QueueTimeout, &mut buf));
+        assert!(buf.is_empty());
+
+        for framing in [Framing::L3, Framing::L3V2] {
+            let mut buf = vec![];
+            assert!(framing
+                .codec()
+                .encode_error(Some(7), ErrorCode::QueueTimeout, &mut buf));
+            assert_eq!([&Frame::error(7).as_bytes()[..], &[1]].concat(), buf);
+        }
+    }
+}
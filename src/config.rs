@@ -1,63 +1,806 @@
-use serde::Deserialize;
-use std::{error::Error, fs};
+use serde::{Deserialize, Deserializer};
+use std::{error::Error, fs, time::Duration};
+use thiserror::Error;
 use tracing::info;
 
+use crate::codec::Framing;
+
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 pub struct Config {
     pub service: Service,
     pub upstream: Upstream,
+
+    /// Additional listeners that share the upstream pool with `service`.
+    #[serde(default)]
+    pub listeners: Vec<Service>,
+
+    #[serde(default)]
+    pub shutdown: Shutdown,
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 pub struct Service {
+    /// The address to listen on, or `unix:/path/to/socket` for a unix socket.
     pub host: String,
-    pub port: u16,
+    /// Required, unless the listener is a unix socket.
+    #[serde(default)]
+    pub port: Option<u16>,
 
     #[serde(with = "serde_humanize_rs")]
     pub max_msg_len: usize,
+
+    #[serde(default)]
+    pub codec: Framing,
+
+    /// How many requests a client connection can have in flight. No more
+    /// requests are read from it until one of them completes.
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+
+    /// Clients have to connect over TLS if set.
+    #[serde(default)]
+    pub tls: Option<ServiceTls>,
+
+    /// The permissions of the socket file of a unix socket, `0o660` for
+    /// example. Left to the umask if not set.
+    #[serde(default)]
+    pub socket_mode: Option<u32>,
+}
+
+/// The path of the socket if `address` is a unix socket: `unix:/path/to/socket`.
+pub fn unix_socket(address: &str) -> Option<&str> {
+    address.strip_prefix("unix:")
+}
+
+fn default_max_in_flight() -> usize {
+    128
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct ServiceTls {
+    /// PEM file with the certificate chain, starting with the certificate of the listener.
+    pub cert: String,
+    /// PEM file with the private key of the certificate.
+    pub key: String,
+    /// Protocols to negotiate with ALPN, in order of preference. Clients that
+    /// offer ALPN but none of these protocols are refused.
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    /// PEM file with the CA certificates that client certificates are verified
+    /// against. Clients have to present a certificate if set.
+    #[serde(default)]
+    pub client_ca: Option<String>,
+    /// Client names that can connect. Any client can connect if empty.
+    ///
+    /// The names of a client are the DNS and URI subject alternative names of
+    /// its certificate, and the common name of its subject. Needs `client_ca`.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Client names that are refused, even if another name of the client is
+    /// in `allow`. Needs `client_ca`.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// How often the certificate and key files are checked for changes. A new
+    /// certificate is picked up by the connections that come after it without
+    /// a restart.
+    #[serde(with = "serde_humanize_rs", default = "default_tls_reload_interval")]
+    pub reload_interval: Duration,
+}
+
+fn default_tls_reload_interval() -> Duration {
+    Duration::from_secs(60)
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 pub struct Upstream {
-    pub hosts: Vec<String>,
+    pub hosts: Vec<UpstreamHost>,
+    /// Connections per host, unless the host sets its own.
     pub connections: usize,
+
+    #[serde(default)]
+    pub codec: Framing,
+    /// How many requests without a request id can be in flight on each
+    /// connection. The upstream has to respond to them in order.
+    #[serde(default = "default_pipeline_depth")]
+    pub pipeline_depth: usize,
+    /// How many requests with a request id can be in flight on each
+    /// connection. At most 65536, the number of request ids.
+    #[serde(default = "default_upstream_max_in_flight")]
+    pub max_in_flight: usize,
+    /// Connections to the hosts use TLS if set.
+    #[serde(default)]
+    pub tls: Option<UpstreamTls>,
+
+    /// Requests that wait in the queue for longer than this fail.
+    #[serde(with = "serde_humanize_rs", default = "default_queue_timeout")]
+    pub queue_timeout: Duration,
+    /// How requests are assigned to hosts.
+    #[serde(default)]
+    pub balancer: Balancer,
+
+    /// How many requests can wait in the queue of each host. Unbounded if not set.
+    #[serde(default)]
+    pub queue_capacity: Option<usize>,
+    /// What happens to requests that don't fit in the queue.
+    #[serde(default)]
+    pub queue_overflow: Overflow,
+
+    /// Shed queued requests earlier while the queue is standing.
+    #[serde(default)]
+    pub codel: Option<Codel>,
+
+    /// Retry failed requests on other hosts.
+    #[serde(default)]
+    pub retry: Option<Retry>,
+
+    /// Stop sending requests to hosts, or the whole cluster, after
+    /// consecutive failures.
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreaker>,
+
+    /// Send idempotent requests to a second host when the first is slow.
+    #[serde(default)]
+    pub hedge: Option<Hedge>,
+
+    /// Requests that don't get a response in time fail and the connection
+    /// they were sent on is reset.
+    #[serde(default, deserialize_with = "optional_duration")]
+    pub response_timeout: Option<Duration>,
+
+    /// Actively probe the hosts. Unhealthy hosts don't get any requests.
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+
+    /// Eject hosts that fail too many requests.
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetection>,
+
+    /// Resolve the host names again while running. They are only resolved
+    /// when connecting if not set.
+    #[serde(default)]
+    pub dns: Option<Dns>,
+}
+
+/// No hosts, one connection per host, and everything else as it is when left
+/// out of the config.
+impl Default for Upstream {
+    fn default() -> Self {
+        Upstream {
+            hosts: vec![],
+            connections: 1,
+            codec: Default::default(),
+            pipeline_depth: default_pipeline_depth(),
+            max_in_flight: default_upstream_max_in_flight(),
+            tls: None,
+            queue_timeout: default_queue_timeout(),
+            balancer: Default::default(),
+            queue_capacity: None,
+            queue_overflow: Default::default(),
+            codel: None,
+            retry: None,
+            circuit_breaker: None,
+            hedge: None,
+            response_timeout: None,
+            health_check: None,
+            outlier_detection: None,
+            dns: None,
+        }
+    }
+}
+
+/// An upstream host. Either just the address, or a table that sets the
+/// weight and the connection count of the host.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(from = "HostEntry")]
+pub struct UpstreamHost {
+    /// `host:port`, or `unix:/path/to/socket` for a unix socket.
+    pub addr: String,
+    /// Hosts get requests in proportion to their weight.
+    pub weight: u32,
+    pub connections: Option<usize>,
+}
+
+impl From<String> for UpstreamHost {
+    fn from(addr: String) -> Self {
+        UpstreamHost {
+            addr,
+            weight: default_weight(),
+            connections: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HostEntry {
+    Addr(String),
+    Table {
+        addr: String,
+        #[serde(default = "default_weight")]
+        weight: u32,
+        #[serde(default)]
+        connections: Option<usize>,
+    },
+}
+
+impl From<HostEntry> for UpstreamHost {
+    fn from(entry: HostEntry) -> Self {
+        match entry {
+            HostEntry::Addr(addr) => UpstreamHost::from(addr),
+            HostEntry::Table {
+                addr,
+                weight,
+                connections,
+            } => UpstreamHost {
+                addr,
+                weight,
+                connections,
+            },
+        }
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum Balancer {
+    /// Smooth weighted round robin.
+    #[default]
+    RoundRobin,
+    /// The host with the fewest outstanding requests relative to its weight.
+    LeastOutstanding,
+    /// The host with fewer outstanding requests out of two random ones.
+    PowerOfTwoChoices,
+    /// The host with the lower expected latency out of two random ones.
+    PeakEwma {
+        /// How quickly old latencies are forgotten.
+        #[serde(with = "serde_humanize_rs", default = "default_ewma_decay")]
+        decay: Duration,
+    },
+    /// Requests with the same key go to the same host.
+    ConsistentHash(ConsistentHash),
+}
+
+pub fn default_ewma_decay() -> Duration {
+    Duration::from_secs(10)
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct ConsistentHash {
+    pub key: HashKey,
+    /// A host doesn't get more than this percentage of the average load,
+    /// its requests spill over to the next host on the ring instead.
+    #[serde(default = "default_max_load_percent")]
+    pub max_load_percent: u32,
+}
+
+fn default_max_load_percent() -> u32 {
+    125
+}
+
+/// Where the hash key of a request comes from.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    /// The payload bytes in `start..end`, or as many of them as there are.
+    Range { start: usize, end: usize },
+    /// The payload up to the first occurrence of the delimiter, or all of it.
+    Delimiter(String),
+    /// The request id of v2 frames. v1 requests are spread evenly.
+    RequestId,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Fail the new request.
+    #[default]
+    Reject,
+    /// Fail the request that has been waiting the longest to make room.
+    DropOldest,
+    /// Stop reading from the clients until there is room.
+    Wait,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct UpstreamTls {
+    /// PEM file with the CA certificates that the hosts are verified against.
+    /// Required unless `insecure_skip_verify` is set.
+    #[serde(default)]
+    pub ca: Option<String>,
+    /// PEM files with a client certificate chain and its private key, for
+    /// hosts that require mTLS.
+    #[serde(default)]
+    pub cert: Option<String>,
+    #[serde(default)]
+    pub key: Option<String>,
+    /// The name sent with SNI and verified against the certificates of the
+    /// hosts. Defaults to the host part of their address.
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// Accepts any certificate the hosts present. Only meant for tests.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+fn default_pipeline_depth() -> usize {
+    1
+}
+
+fn default_upstream_max_in_flight() -> usize {
+    1024
+}
+
+fn default_queue_timeout() -> Duration {
+    Duration::from_millis(4)
+}
+
+/// Requests that fail before they are sent to the upstream are retried, and
+/// so are the failed v2 requests that have the idempotent flag set.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct Retry {
+    /// How many times a request can be retried.
+    pub attempts: u32,
+    /// Retries can't be more than this percentage of the requests in a
+    /// `budget_window`, which keeps them from piling on to an outage.
+    pub budget_percent: u32,
+    /// Retries that are allowed in every window regardless of the budget, so
+    /// that low traffic can be retried too.
+    #[serde(default = "default_min_retries")]
+    pub min_retries: u32,
+    #[serde(with = "serde_humanize_rs", default = "default_budget_window")]
+    pub budget_window: Duration,
+}
+
+/// Requests fail right away while a circuit is open, instead of waiting for
+/// the queue timeout. Failed and timed out requests count as failures.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct CircuitBreaker {
+    /// Consecutive failures that open the circuit of a host.
+    pub consecutive_failures: u32,
+    /// How long a circuit stays open before trial requests are let through.
+    #[serde(with = "serde_humanize_rs")]
+    pub open_time: Duration,
+    /// Trial requests that have to succeed to close the circuit again.
+    #[serde(default = "default_half_open_requests")]
+    pub half_open_requests: u32,
+    /// Consecutive failures across all the hosts that open the circuit of the
+    /// whole cluster. Disabled if not set.
+    #[serde(default)]
+    pub cluster_consecutive_failures: Option<u32>,
+}
+
+fn default_half_open_requests() -> u32 {
+    1
+}
+
+/// Idempotent v2 requests that haven't been answered after the `percentile`
+/// response time of the recent requests are sent to a second host. Whichever
+/// host answers first wins.
+///
+/// Roughly `100 - percentile` percent of those requests are hedged, which
+/// bounds the extra load.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct Hedge {
+    pub percentile: u32,
+    /// Requests aren't hedged any sooner than this, however fast the upstreams are.
+    #[serde(with = "serde_humanize_rs", default = "default_hedge_min_delay")]
+    pub min_delay: Duration,
+}
+
+fn default_hedge_min_delay() -> Duration {
+    Duration::from_millis(1)
+}
+
+fn default_min_retries() -> u32 {
+    10
+}
+
+fn default_budget_window() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// Adaptive queue timeouts, modeled on CoDel.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct Codel {
+    /// The queue is standing if every request in an interval waited longer
+    /// than this. Requests are then shed once they wait for `target`
+    /// instead of `queue_timeout`.
+    #[serde(with = "serde_humanize_rs")]
+    pub target: Duration,
+    #[serde(with = "serde_humanize_rs")]
+    pub interval: Duration,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// Sent to the host using the upstream codec, as a v2 request with id 0
+    /// if the codec carries request ids. Either text, or a table with the
+    /// bytes in `hex`.
+    #[serde(deserialize_with = "payload")]
+    pub payload: Vec<u8>,
+    /// The response has to start with this to count as a success. Text or
+    /// hex, like `payload`.
+    #[serde(default, deserialize_with = "payload")]
+    pub expect: Vec<u8>,
+
+    #[serde(with = "serde_humanize_rs")]
+    pub interval: Duration,
+    #[serde(with = "serde_humanize_rs")]
+    pub timeout: Duration,
+
+    /// Consecutive successes it takes for an unhealthy host to become healthy.
+    pub healthy_threshold: u32,
+    /// Consecutive failures it takes for a healthy host to become unhealthy.
+    pub unhealthy_threshold: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PayloadEntry {
+    Text(String),
+    Hex { hex: String },
+}
+
+fn payload<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match PayloadEntry::deserialize(deserializer)? {
+        PayloadEntry::Text(text) => Ok(text.into_bytes()),
+        PayloadEntry::Hex { hex } => decode_hex(&hex).ok_or_else(|| {
+            serde::de::Error::custom(format!("{hex:?} isn't an even number of hex digits"))
+        }),
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // Whitespace can be used to group the bytes
+    let digits = hex
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<Vec<_>>>()?;
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+
+    Some(
+        digits
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect(),
+    )
+}
+
+/// Every host name is resolved again once the TTL of its records runs out.
+/// Each address it resolves to gets the connections of the host, and the
+/// connections to addresses that are gone are drained and closed.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct Dns {
+    /// Records are resolved again no sooner than this, even with a shorter TTL.
+    /// Also how long to wait before trying again after a failed lookup. Has
+    /// to be longer than 0.
+    #[serde(with = "serde_humanize_rs", default = "default_dns_min_ttl")]
+    pub min_ttl: Duration,
+    /// Records are resolved again no later than this, even with a longer TTL.
+    #[serde(with = "serde_humanize_rs", default = "default_dns_max_ttl")]
+    pub max_ttl: Duration,
+    /// How long the connections to a removed address have to finish the
+    /// requests they have in flight before they are closed.
+    #[serde(with = "serde_humanize_rs", default = "default_dns_drain_timeout")]
+    pub drain_timeout: Duration,
+}
+
+fn default_dns_min_ttl() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_dns_max_ttl() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_dns_drain_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct OutlierDetection {
+    /// How often the hosts are evaluated. Failure percentages are computed
+    /// over the requests of the last interval.
+    #[serde(with = "serde_humanize_rs")]
+    pub interval: Duration,
+
+    /// Consecutive failed requests that get a host ejected. 0 disables it.
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    /// The percentage of failed requests in an interval that gets a host
+    /// ejected. 0 disables it.
+    #[serde(default)]
+    pub failure_percentage: u32,
+    /// Hosts with fewer requests in an interval aren't judged by their failure percentage.
+    #[serde(default)]
+    pub min_requests: u32,
+    /// Responses that take longer than this count as failures.
+    #[serde(default, deserialize_with = "optional_duration")]
+    pub slow_response: Option<Duration>,
+
+    /// How long the first ejection lasts. Every repeat ejection adds another
+    /// `base_ejection_time`, up to `max_ejection_time`.
+    #[serde(with = "serde_humanize_rs")]
+    pub base_ejection_time: Duration,
+    #[serde(with = "serde_humanize_rs")]
+    pub max_ejection_time: Duration,
+    /// The share of hosts that can be ejected at the same time, rounded down.
+    /// The last host that isn't ejected never is.
+    pub max_ejection_percent: u32,
 }
 
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct Shutdown {
+    /// How long in flight requests get to finish once a shutdown starts. The
+    /// clients that are still connected after that are dropped.
+    #[serde(with = "serde_humanize_rs", default = "default_drain_timeout")]
+    pub drain_timeout: Duration,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            drain_timeout: default_drain_timeout(),
+        }
+    }
+}
+
+fn default_drain_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn optional_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    serde_humanize_rs::deserialize(deserializer).map(Some)
+}
+
+/// A config that parses, but has settings that can't work together.
+#[derive(Debug, Error)]
+#[error("invalid config: {0}")]
+pub struct InvalidConfig(String);
+
 impl Config {
     pub fn read_from_file(conf_path: &str) -> Result<Config, Box<dyn Error>> {
         info!(path = conf_path, "👀 reading the config");
         let conf_data = fs::read_to_string(conf_path)?;
         let config: Config = toml::from_str(&conf_data)?;
+        config.validate()?;
 
         Ok(config)
     }
+
+    /// Checks the settings that depend on each other.
+    pub fn validate(&self) -> Result<(), InvalidConfig> {
+        // A host without weight would never get a request
+        if let Some(host) = self.upstream.hosts.iter().find(|host| host.weight == 0) {
+            return Err(InvalidConfig(format!(
+                "the weight of {} has to be at least 1",
+                host.addr
+            )));
+        }
+
+        // Short TTLs would have the names resolved again back to back
+        if self
+            .upstream
+            .dns
+            .as_ref()
+            .is_some_and(|dns| dns.min_ttl.is_zero())
+        {
+            return Err(InvalidConfig(String::from(
+                "the min_ttl of dns has to be longer than 0",
+            )));
+        }
+
+        // Every request in flight on a connection needs an id of its own
+        if !(1..=1 << 16).contains(&self.upstream.max_in_flight) {
+            return Err(InvalidConfig(String::from(
+                "the max_in_flight of the upstream has to be between 1 and 65536",
+            )));
+        }
+
+        for service in self.services() {
+            // Otherwise it would listen on whatever port the system picks
+            if service.port.is_none() && unix_socket(&service.host).is_none() {
+                return Err(InvalidConfig(format!(
+                    "{} needs a port, it isn't a unix socket",
+                    service.host
+                )));
+            }
+
+            // Requests and responses of the listener go through both codecs
+            let codecs = [service.codec, self.upstream.codec];
+            if let Some(codec) = codecs
+                .into_iter()
+                .find(|codec| service.max_msg_len > codec.codec().max_msg_len())
+            {
+                return Err(InvalidConfig(format!(
+                    "the max_msg_len of {} is longer than the {codec:?} codec can carry",
+                    service.host
+                )));
+            }
+
+            let Some(tls) = &service.tls else {
+                continue;
+            };
+
+            // Clients without a certificate have no names to match
+            if tls.client_ca.is_none() && !(tls.allow.is_empty() && tls.deny.is_empty()) {
+                return Err(InvalidConfig(format!(
+                    "the allow and deny lists of {} need a client_ca",
+                    service.host
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// All the listeners, starting with `service`.
+    pub fn services(&self) -> impl Iterator<Item = &Service> {
+        std::iter::once(&self.service).chain(self.listeners.iter())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{error::Error, path::PathBuf};
+    use std::{error::Error, path::PathBuf, time::Duration};
 
-    use super::Config;
+    use super::{Config, HealthCheck};
+    use crate::codec::Framing;
 
-    #[test]
-    fn properly_deserilizes_the_config() -> Result<(), Box<dyn Error>> {
+    fn read(name: &str) -> Result<Config, Box<dyn Error>> {
         let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        d.push("config/config.toml");
+        d.push("config");
+        d.push(name);
         let conf_path = d.into_os_string().into_string().expect("conf path faliure");
-        let conf = Config::read_from_file(&conf_path)?;
+        Config::read_from_file(&conf_path)
+    }
+
+    #[test]
+    fn the_default_config_only_needs_the_upstreams() -> Result<(), Box<dyn Error>> {
+        let conf = read("config.toml")?;
+
+        assert_eq!(32, conf.service.max_msg_len);
+        assert_eq!(50, conf.upstream.connections);
+        assert_eq!(2, conf.upstream.hosts.len());
+        assert_eq!(Framing::L3, conf.upstream.codec);
+        assert!(conf.upstream.tls.is_none());
+        assert!(conf.listeners.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn properly_deserilizes_the_config() -> Result<(), Box<dyn Error>> {
+        let conf = read("example.toml")?;
 
         let expected = Config {
             service: super::Service {
                 host: String::from("0.0.0.0"),
-                port: 8000,
+                port: Some(8000),
                 max_msg_len: 32,
+                codec: Framing::L3,
+                max_in_flight: 64,
+                tls: None,
+                socket_mode: None,
             },
             upstream: super::Upstream {
                 hosts: vec![
-                    String::from("127.0.0.1:4444"),
-                    String::from("127.0.0.1:4445"),
+                    super::UpstreamHost::from(String::from("127.0.0.1:4444")),
+                    super::UpstreamHost {
+                        addr: String::from("127.0.0.1:4445"),
+                        weight: 3,
+                        connections: Some(100),
+                    },
                 ],
                 connections: 50,
+                codec: Framing::U32Be,
+                pipeline_depth: 8,
+                max_in_flight: 256,
+                tls: Some(super::UpstreamTls {
+                    ca: Some(String::from("/etc/l3/upstream-ca.pem")),
+                    cert: Some(String::from("/etc/l3/client-cert.pem")),
+                    key: Some(String::from("/etc/l3/client-key.pem")),
+                    server_name: Some(String::from("upstream.internal")),
+                    insecure_skip_verify: false,
+                }),
+                queue_timeout: Duration::from_millis(50),
+                balancer: super::Balancer::ConsistentHash(super::ConsistentHash {
+                    key: super::HashKey::Delimiter(String::from(":")),
+                    max_load_percent: 150,
+                }),
+                queue_capacity: Some(10000),
+                queue_overflow: super::Overflow::DropOldest,
+                codel: Some(super::Codel {
+                    target: Duration::from_millis(5),
+                    interval: Duration::from_millis(100),
+                }),
+                retry: Some(super::Retry {
+                    attempts: 2,
+                    budget_percent: 20,
+                    min_retries: 10,
+                    budget_window: Duration::from_secs(10),
+                }),
+                circuit_breaker: Some(super::CircuitBreaker {
+                    consecutive_failures: 5,
+                    open_time: Duration::from_secs(10),
+                    half_open_requests: 1,
+                    cluster_consecutive_failures: Some(100),
+                }),
+                hedge: Some(super::Hedge {
+                    percentile: 95,
+                    min_delay: Duration::from_millis(5),
+                }),
+                response_timeout: Some(Duration::from_secs(2)),
+                health_check: Some(super::HealthCheck {
+                    payload: b"PING".to_vec(),
+                    expect: b"PONG".to_vec(),
+                    interval: Duration::from_secs(5),
+                    timeout: Duration::from_secs(1),
+                    healthy_threshold: 2,
+                    unhealthy_threshold: 3,
+                }),
+                outlier_detection: Some(super::OutlierDetection {
+                    interval: Duration::from_secs(10),
+                    consecutive_failures: 5,
+                    failure_percentage: 50,
+                    min_requests: 20,
+                    slow_response: Some(Duration::from_secs(1)),
+                    base_ejection_time: Duration::from_secs(30),
+                    max_ejection_time: Duration::from_secs(300),
+                    max_ejection_percent: 50,
+                }),
+                dns: Some(super::Dns {
+                    min_ttl: Duration::from_secs(5),
+                    max_ttl: Duration::from_secs(300),
+                    drain_timeout: Duration::from_secs(30),
+                }),
+            },
+            listeners: vec![
+                super::Service {
+                    host: String::from("::"),
+                    port: Some(8001),
+                    max_msg_len: 1024,
+                    codec: Framing::L3,
+                    max_in_flight: 128,
+                    tls: Some(super::ServiceTls {
+                        cert: String::from("/etc/l3/cert.pem"),
+                        key: String::from("/etc/l3/key.pem"),
+                        alpn: vec![String::from("l3")],
+                        client_ca: Some(String::from("/etc/l3/client-ca.pem")),
+                        allow: vec![
+                            String::from("billing.internal"),
+                            String::from("spiffe://internal/orders"),
+                        ],
+                        deny: vec![],
+                        reload_interval: Duration::from_secs(60),
+                    }),
+                    socket_mode: None,
+                },
+                super::Service {
+                    host: String::from("unix:/run/l3/l3.sock"),
+                    port: None,
+                    max_msg_len: 1024,
+                    codec: Framing::L3,
+                    max_in_flight: 128,
+                    tls: None,
+                    socket_mode: Some(0o660),
+                },
+            ],
+            shutdown: super::Shutdown {
+                drain_timeout: Duration::from_secs(10),
             },
         };
 
@@ -65,4 +808,153 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn client_rules_need_client_certificates() {
+        let conf = |tls: &str| {
+            let conf = format!(
+                r#"
+                [service]
+                host = "0.0.0.0"
+                port = 8000
+                max_msg_len = "32b"
+
+                [service.tls]
+                cert = "cert.pem"
+                key = "key.pem"
+                {tls}
+
+                [upstream]
+                hosts = ["127.0.0.1:4444"]
+                connections = 1
+                "#
+            );
+            toml::from_str::<Config>(&conf).unwrap().validate()
+        };
+
+        assert!(conf("").is_ok());
+        assert!(conf(r#"client_ca = "ca.pem""#).is_ok());
+        assert!(conf(r#"allow = ["billing.internal"]"#).is_err());
+        assert!(conf(r#"deny = ["billing.internal"]"#).is_err());
+        assert!(conf("client_ca = \"ca.pem\"\nallow = [\"billing.internal\"]").is_ok());
+    }
+
+    #[test]
+    fn messages_have_to_fit_the_codecs() {
+        let conf = |service: &str, upstream: &str| {
+            let conf = format!(
+                r#"
+                [service]
+                host = "0.0.0.0"
+                port = 8000
+                max_msg_len = "64KiB"
+                codec = "{service}"
+
+                [upstream]
+                hosts = ["127.0.0.1:4444"]
+                connections = 1
+                codec = "{upstream}"
+                "#
+            );
+            toml::from_str::<Config>(&conf).unwrap().validate()
+        };
+
+        assert!(conf("l3", "u32_be").is_ok());
+        assert!(conf("u16_be", "l3").is_err());
+        assert!(conf("l3", "u16_be").is_err());
+    }
+
+    #[test]
+    fn hosts_need_a_weight() {
+        let conf = |weight: u32| {
+            let conf = format!(
+                r#"
+                [service]
+                host = "0.0.0.0"
+                port = 8000
+                max_msg_len = "32b"
+
+                [upstream]
+                hosts = [{{ addr = "127.0.0.1:4444", weight = {weight} }}]
+                connections = 1
+                "#
+            );
+            toml::from_str::<Config>(&conf).unwrap().validate()
+        };
+
+        assert!(conf(1).is_ok());
+        assert!(conf(0).is_err());
+    }
+
+    #[test]
+    fn health_check_payloads_can_be_hex() {
+        let check = |payload: &str| {
+            let conf = format!(
+                r#"
+                payload = {payload}
+                interval = "5s"
+                timeout = "1s"
+                healthy_threshold = 2
+                unhealthy_threshold = 3
+                "#
+            );
+            toml::from_str::<HealthCheck>(&conf).map(|check| check.payload)
+        };
+
+        assert_eq!(b"PING".to_vec(), check(r#""PING""#).unwrap());
+        assert_eq!(
+            vec![0x00, 0x01, 0xca, 0xfe],
+            check(r#"{ hex = "0001 CAfe" }"#).unwrap()
+        );
+        assert!(check(r#"{ hex = "abc" }"#).is_err());
+        assert!(check(r#"{ hex = "zz" }"#).is_err());
+    }
+
+    #[test]
+    fn names_are_not_resolved_back_to_back() {
+        let conf = |min_ttl: &str| {
+            let conf = format!(
+                r#"
+                [service]
+                host = "0.0.0.0"
+                port = 8000
+                max_msg_len = "32b"
+
+                [upstream]
+                hosts = ["localhost:4444"]
+                connections = 1
+
+                [upstream.dns]
+                min_ttl = "{min_ttl}"
+                "#
+            );
+            toml::from_str::<Config>(&conf).unwrap().validate()
+        };
+
+        assert!(conf("1ms").is_ok());
+        assert!(conf("0s").is_err());
+    }
+
+    #[test]
+    fn tcp_listeners_need_a_port() {
+        let conf = |host: &str, port: &str| {
+            let conf = format!(
+                r#"
+                [service]
+                host = "{host}"
+                {port}
+                max_msg_len = "32b"
+
+                [upstream]
+                hosts = ["127.0.0.1:4444"]
+                connections = 1
+                "#
+            );
+            toml::from_str::<Config>(&conf).unwrap().validate()
+        };
+
+        assert!(conf("0.0.0.0", "port = 8000").is_ok());
+        assert!(conf("0.0.0.0", "").is_err());
+        assert!(conf("unix:/run/l3/l3.sock", "").is_ok());
+    }
 }
@@ -1,33 +1,130 @@
 use std::io;
 
-use tracing::info;
+use futures::future::try_join_all;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{info, warn};
 
-use crate::{config::Config, downstream::server::Server, upstream::pool::Pool};
+use crate::{
+    buffer::BufferPool,
+    config::Config,
+    downstream::server::Server,
+    upstream::{dns::Resolve, pool::Pool},
+};
 
 pub struct Daemon {
+    config: &'static Config,
     upstream_pool: &'static Pool,
-    downstream_server: &'static Server<Pool>,
+    downstream_servers: Vec<&'static Server<Pool>>,
+    downstream_clients: TaskTracker,
+    shutdown: CancellationToken,
+    abort: CancellationToken,
+}
+
+/// Gracefully stops the daemon it was created from.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shutdown: CancellationToken,
+}
+
+impl ShutdownHandle {
+    /// Stops accepting new connections and drains the existing ones. `Daemon::run`
+    /// returns once the upstream connections are closed.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
 }
 
 impl Daemon {
-    pub fn new(conf: &'static mut Config) -> Self {
+    pub fn new(conf: &'static mut Config) -> io::Result<Self> {
+        Self::build(conf, None)
+    }
+
+    /// Resolves the upstream host names with `resolver` instead of the name
+    /// servers of the system when `[upstream.dns]` is set.
+    pub fn with_resolver(
+        conf: &'static mut Config,
+        resolver: impl Resolve + 'static,
+    ) -> io::Result<Self> {
+        Self::build(conf, Some(Box::new(resolver)))
+    }
+
+    fn build(conf: &'static mut Config, resolver: Option<Box<dyn Resolve>>) -> io::Result<Self> {
         info!("instantiating daemon");
-        let upstream_pool: &'static mut Pool = Box::leak(Box::new(Pool::new(conf)));
-        let downstream_server: &'static mut Server<_> =
-            Box::leak(Box::new(Server::new(conf, upstream_pool)));
+        let shutdown = CancellationToken::new();
+        let abort = CancellationToken::new();
+        let downstream_clients = TaskTracker::new();
+
+        let buffers: &'static BufferPool = Box::leak(Box::new(BufferPool::new()));
+        let upstream_pool: &'static mut Pool =
+            Box::leak(Box::new(Pool::new(conf, buffers, resolver)?));
+        let downstream_servers = conf
+            .services()
+            .map(|service| {
+                let server = Server::new(
+                    service,
+                    upstream_pool,
+                    buffers,
+                    shutdown.clone(),
+                    downstream_clients.clone(),
+                    abort.clone(),
+                );
+                &*Box::leak(Box::new(server))
+            })
+            .collect();
 
-        Daemon {
+        Ok(Daemon {
+            config: conf,
             upstream_pool,
-            downstream_server,
+            downstream_servers,
+            downstream_clients,
+            shutdown,
+            abort,
+        })
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shutdown: self.shutdown.clone(),
         }
     }
 
+    /// The upstream pool, to read its circuit states, queue depth and hedging
+    /// metrics while the daemon runs.
+    pub fn upstream_pool(&self) -> &'static Pool {
+        self.upstream_pool
+    }
+
+    /// Runs until the shutdown handle is triggered.
     pub async fn run(&self) -> io::Result<()> {
         info!("running the daemon");
-        // TODO: need to handle graceful shutdowns
 
-        self.upstream_pool.start();
-        self.downstream_server.start().await?;
+        tokio::select! {
+            _ = self.shutdown.cancelled() => {}
+            _ = self.upstream_pool.start() => {
+                try_join_all(self.downstream_servers.iter().map(|s| s.start())).await?;
+            }
+        }
+
+        let drain_timeout = self.config.shutdown.drain_timeout;
+        info!(
+            clients = self.downstream_clients.len(),
+            ?drain_timeout,
+            "draining downstream connections"
+        );
+
+        self.downstream_clients.close();
+        let drained = tokio::time::timeout(drain_timeout, self.downstream_clients.wait()).await;
+        if drained.is_err() {
+            warn!(
+                clients = self.downstream_clients.len(),
+                "drain timeout elapsed, dropping the remaining requests"
+            );
+            self.abort.cancel();
+            self.downstream_clients.wait().await;
+        }
+
+        self.upstream_pool.close().await;
+        info!("daemon stopped");
 
         Ok(())
     }
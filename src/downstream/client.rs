@@ -3,85 +3,578 @@ use std::{
     sync::Arc,
 };
 
+use bytes::Bytes;
+use futures::{stream::FuturesOrdered, StreamExt};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    sync::Mutex,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::{mpsc, oneshot, Mutex, OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
 };
-use tracing::{debug, warn};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info_span, warn, Instrument, Span};
 
-use crate::{config::Config, frame::Frame, upstream::pool::AsyncRequestQueue};
+use crate::{
+    buffer::BufferPool,
+    codec::{self, Codec, MAX_HEADER_LEN},
+    config::Service,
+    frame::{ErrorCode, Frame, FrameError, V1},
+    upstream::pool::{AsyncRequestQueue, RequestError},
+};
 
 pub struct Client<T, U>
 where
     T: AsyncReadExt,
     U: AsyncRequestQueue + 'static,
 {
-    conf: &'static Config,
+    service: &'static Service,
     stream: T,
     queue: &'static U,
+    buffers: &'static BufferPool,
+    shutdown: CancellationToken,
+    /// Who the client is, if it authenticated with a client certificate.
+    identity: Option<String>,
 }
 
 impl<T, U> Client<T, U>
 where
     T: AsyncReadExt + AsyncWriteExt + Unpin,
-    U: AsyncRequestQueue,
+    U: AsyncRequestQueue + Sync,
 {
-    pub fn new(stream: T, conf: &'static Config, queue: &'static U) -> Self {
+    pub fn new(
+        stream: T,
+        service: &'static Service,
+        queue: &'static U,
+        buffers: &'static BufferPool,
+        shutdown: CancellationToken,
+        identity: Option<String>,
+    ) -> Self {
         Client {
             stream,
-            conf,
+            service,
             queue,
+            buffers,
+            shutdown,
+            identity,
         }
     }
 
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    /// Serves the client until it disconnects.
+    ///
+    /// Requests are sent to the pool as soon as they are read, up to
+    /// `max_in_flight` of them at a time. v1 responses are written back
+    /// without a header, in the order the requests were read. v2 responses
+    /// are written back with the request id of the original request, in
+    /// whatever order they complete.
+    ///
+    /// A failed v2 request gets an error frame and the connection stays open.
+    /// v1 clients have no way to receive an error, so their connection is
+    /// closed instead. So is any connection that sends a malformed header,
+    /// since there is no telling where the next frame starts.
+    ///
+    /// Once `shutdown` is cancelled no new requests are read. The requests
+    /// that are already in flight are answered before returning. The ones
+    /// of a client that disconnects are given up on right away.
+    ///
+    /// Everything that is logged for the client carries its identity.
     pub async fn serve(&mut self) -> io::Result<()> {
-        let b: Vec<u8> = vec![0; self.conf.service.max_msg_len];
-        let buffer = Arc::new(Mutex::new(b));
-
-        let mut n: usize;
-
-        loop {
-            let downstream_buff = buffer.clone();
-            let upstream_buff = buffer.clone();
-
-            let mut downstream_mutex = downstream_buff.lock().await;
-            let buffer: &mut Vec<u8> = downstream_mutex.as_mut();
-
-            _ = self.stream.read_exact(&mut buffer[0..8]).await?;
-            let frame = Frame::from_bytes(
-                &buffer[0..8]
-                    .try_into()
-                    .expect("couldn't convert buffer into [u8;8]"),
-            )
-            .map_err(|_| io::ErrorKind::Other)?; // TODO: need to handle the FrameError
-            debug!(?frame, "read a frame");
-
-            if frame.msg_len as usize > self.conf.service.max_msg_len {
-                warn!(
-                    frame.msg_len,
-                    self.conf.service.max_msg_len, "payload size is greater than the maximum"
-                );
-
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "payload size is greater than the maximum",
-                ));
+        let span = info_span!("client", identity = self.identity.as_deref());
+        self.serve_requests().instrument(span).await
+    }
+
+    async fn serve_requests(&mut self) -> io::Result<()> {
+        let codec = self.service.codec.codec();
+        let (any_order, any_order_rx) = mpsc::unbounded_channel();
+        let (in_order, in_order_rx) = mpsc::unbounded_channel();
+        let responses = Responses {
+            any_order,
+            in_order,
+        };
+        let (reader, writer) = tokio::io::split(&mut self.stream);
+        let writer = Mutex::new(writer);
+
+        let reading = read_requests(
+            self.service,
+            self.queue,
+            self.buffers,
+            reader,
+            &writer,
+            responses,
+            &self.shutdown,
+        );
+        let writing = write_responses(codec, self.buffers, &writer, any_order_rx, in_order_rx);
+        tokio::pin!(writing);
+
+        tokio::select! {
+            r = reading => {
+                // Only shutting down leaves the requests in flight to finish
+                let _requests = r?;
+                // The response channels close once the in flight requests are done
+                writing.await
+            }
+            r = &mut writing => r,
+        }
+    }
+}
+
+async fn read_requests<T, U>(
+    service: &'static Service,
+    queue: &'static U,
+    buffers: &'static BufferPool,
+    mut reader: ReadHalf<T>,
+    writer: &Mutex<WriteHalf<T>>,
+    responses: Responses,
+    shutdown: &CancellationToken,
+) -> io::Result<JoinSet<()>>
+where
+    T: AsyncRead + AsyncWrite,
+    U: AsyncRequestQueue + Sync,
+{
+    let codec = service.codec.codec();
+    let in_flight = Arc::new(Semaphore::new(service.max_in_flight.max(1)));
+
+    // Dropping it aborts the requests, when the client is gone there is no
+    // one left to answer
+    let mut requests = JoinSet::new();
+
+    loop {
+        // Not reading while the queue is full, or while the client has too
+        // many requests in flight, pushes back on the client
+        let read = async {
+            // The semaphore is never closed
+            let permit = in_flight.clone().acquire_owned().await.unwrap();
+            queue.ready().await;
+            (permit, codec::read_frame(codec, &mut reader).await)
+        };
+
+        let (permit, read) = tokio::select! {
+            _ = shutdown.cancelled() => {
+                debug!("shutting down, no longer reading requests");
+                return Ok(requests);
+            }
+            read = read => read,
+        };
+
+        let frame = match read? {
+            Ok(frame) => frame,
+            Err(
+                e @ FrameError::InvalidRequestVersion {
+                    request_id,
+                    msg_len,
+                    ..
+                },
+            ) => {
+                warn!(err = %e, "received an invalid frame");
+                // Skip the payload to get to the next frame
+                let mut payload = (&mut reader).take(msg_len as u64);
+                tokio::io::copy(&mut payload, &mut tokio::io::sink()).await?;
+
+                // Err here means that the writer is already gone
+                let _ = responses.send(Response {
+                    frame: Frame::with_request_id(request_id, msg_len),
+                    result: Err(Failure {
+                        code: ErrorCode::from(&e),
+                        error: io::Error::new(io::ErrorKind::InvalidData, e),
+                    }),
+                    _permit: permit,
+                });
+                continue;
+            }
+            Err(e) => {
+                warn!(err = %e, "received an invalid frame");
+                // The request id can't be trusted, so only codecs that can
+                // report errors without one get to tell the client
+                write_error(codec, writer, None, ErrorCode::from(&e)).await?;
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        };
+        debug!(?frame, "read a frame");
+
+        if frame.msg_len as usize > service.max_msg_len {
+            warn!(
+                frame.msg_len,
+                service.max_msg_len, "payload size is greater than the maximum"
+            );
+
+            if frame.request_id().is_some() {
+                // Skip the payload to get to the next frame
+                let mut payload = (&mut reader).take(frame.msg_len as u64);
+                tokio::io::copy(&mut payload, &mut tokio::io::sink()).await?;
+
+                // Err here means that the writer is already gone
+                let _ = responses.send(Response {
+                    frame,
+                    result: Err(Failure {
+                        code: ErrorCode::MessageTooLarge,
+                        error: io::Error::other("payload size is greater than the maximum"),
+                    }),
+                    _permit: permit,
+                });
+                continue;
+            }
+
+            return Err(io::Error::other("payload size is greater than the maximum"));
+        }
+
+        let payload = buffers.read(&mut reader, frame.msg_len as usize).await?;
+        debug!(?payload);
+        let payload = payload.freeze();
+
+        let respond = responses.respond_to(&frame);
+        let request = async move {
+            let result = queue
+                .queue_request(frame.clone(), payload.clone(), service.max_msg_len)
+                .await
+                .map_err(|e| {
+                    warn!(id = frame.request_id(), err = %e, "request failed");
+                    Failure {
+                        code: error_code(&e),
+                        error: io::Error::other(e),
+                    }
+                });
+            buffers.put(payload);
+
+            respond.send(Response {
+                frame,
+                result,
+                _permit: permit,
+            });
+        };
+        requests.spawn(request.instrument(Span::current()));
+        // Otherwise the set would keep every finished request around
+        while requests.try_join_next().is_some() {}
+    }
+}
+
+/// A response, and the room it takes up in the requests in flight of the
+/// client until it's written.
+struct Response {
+    frame: Frame,
+    result: Result<Bytes, Failure>,
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Why a request failed, and what the client is told about it.
+struct Failure {
+    code: ErrorCode,
+    /// What the connection fails with if the codec can't tell the client.
+    error: io::Error,
+}
+
+/// Where the responses go once the requests complete. v2 responses are
+/// written in whatever order they complete. v1 responses don't say which
+/// request they belong to, so they are written in the order the requests
+/// were read.
+#[derive(Clone)]
+struct Responses {
+    any_order: mpsc::UnboundedSender<Response>,
+    in_order: mpsc::UnboundedSender<oneshot::Receiver<Response>>,
+}
+
+impl Responses {
+    fn send(&self, response: Response) -> Result<(), mpsc::error::SendError<Response>> {
+        self.any_order.send(response)
+    }
+
+    /// Keeps a place for the response to `frame`, v1 responses are written
+    /// in the order this is called in.
+    fn respond_to(&self, frame: &Frame) -> Respond {
+        if frame.request_id().is_some() {
+            return Respond::AnyOrder(self.any_order.clone());
+        }
+
+        let (tx, rx) = oneshot::channel();
+        // Err here means that the writer is already gone
+        let _ = self.in_order.send(rx);
+        Respond::InOrder(tx)
+    }
+}
+
+/// Hands over the response to a request to the writer.
+enum Respond {
+    AnyOrder(mpsc::UnboundedSender<Response>),
+    InOrder(oneshot::Sender<Response>),
+}
+
+impl Respond {
+    fn send(self, response: Response) {
+        // Err here means that the writer is already gone
+        match self {
+            Respond::AnyOrder(tx) => {
+                let _ = tx.send(response);
+            }
+            Respond::InOrder(tx) => {
+                let _ = tx.send(response);
+            }
+        }
+    }
+}
+
+async fn write_responses<T>(
+    codec: &'static dyn Codec,
+    buffers: &BufferPool,
+    writer: &Mutex<WriteHalf<T>>,
+    mut any_order: mpsc::UnboundedReceiver<Response>,
+    mut in_order: mpsc::UnboundedReceiver<oneshot::Receiver<Response>>,
+) -> io::Result<()>
+where
+    T: AsyncWrite,
+{
+    let mut header = Vec::with_capacity(MAX_HEADER_LEN);
+    let mut pending = FuturesOrdered::new();
+    loop {
+        let response = tokio::select! {
+            Some(response) = any_order.recv() => response,
+            Some(rx) = in_order.recv() => {
+                pending.push_back(rx);
+                continue;
+            }
+            Some(response) = pending.next(), if !pending.is_empty() => {
+                response.map_err(|_| io::Error::other("the request was interrupted"))?
+            }
+            // All the senders are gone, which means the reader stopped and nothing is in flight
+            else => return Ok(()),
+        };
+
+        let Response { frame, result, .. } = response;
+        let id = frame.request_id();
+        let response = match result {
+            Ok(response) => response,
+            Err(Failure { code, error }) => {
+                debug!(id, ?code, "writing an error frame");
+                if !write_error(codec, writer, id, code).await? {
+                    return Err(error);
+                }
+
+                continue;
             }
+        };
+        debug!(len = response.len(), id, "received a response");
+
+        let frame = match id {
+            Some(id) => Frame::with_request_id(id, response.len() as u32),
+            None => Frame::new(V1, response.len() as u32),
+        };
+        header.clear();
+        codec.encode(&frame, &mut header);
+
+        let mut w = writer.lock().await;
+        w.write_all(&header).await?;
+        w.write_all(&response).await?;
+        // TLS streams hold on to what's written until they're flushed
+        w.flush().await?;
+        drop(w);
+        buffers.put(response);
+    }
+}
+
+/// Writes an error response if the codec supports it. Returns false if it doesn't.
+async fn write_error<T>(
+    codec: &'static dyn Codec,
+    writer: &Mutex<WriteHalf<T>>,
+    request_id: Option<u16>,
+    code: ErrorCode,
+) -> io::Result<bool>
+where
+    T: AsyncWrite,
+{
+    let mut buf = Vec::with_capacity(MAX_HEADER_LEN + 1);
+    if !codec.encode_error(request_id, code, &mut buf) {
+        return Ok(false);
+    }
+
+    let mut w = writer.lock().await;
+    w.write_all(&buf).await?;
+    w.flush().await?;
+    Ok(true)
+}
+
+fn error_code(e: &RequestError) -> ErrorCode {
+    match e {
+        RequestError::QueueTimeout => ErrorCode::QueueTimeout,
+        RequestError::QueueFull => ErrorCode::QueueFull,
+        RequestError::CircuitOpen => ErrorCode::CircuitOpen,
+        RequestError::ResponseTimeout { .. } => ErrorCode::UpstreamTimeout,
+        RequestError::Frame { .. } => ErrorCode::UpstreamProtocolError,
+        RequestError::Upstream { .. }
+        | RequestError::NotSent { .. }
+        | RequestError::HostUnavailable { .. }
+        | RequestError::NoUpstream
+        | RequestError::QueueClosed
+        | RequestError::Interrupted => ErrorCode::UpstreamUnavailable,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, time::Duration};
 
-            n = self
-                .stream
-                .read_exact(&mut buffer[0..frame.msg_len as usize])
-                .await?;
+    use bytes::Bytes;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::Notify,
+    };
+    use tokio_util::sync::CancellationToken;
 
-            debug!(buf=?buffer[0..frame.msg_len as usize]);
-            drop(downstream_mutex);
+    use crate::{
+        buffer::BufferPool,
+        codec::Framing,
+        config::Service,
+        frame::{ErrorCode, Frame, HEADER_LEN},
+        upstream::pool::{AsyncRequestQueue, Outcome},
+    };
 
-            n = self.queue.queue_request(upstream_buff, n).await?;
-            debug!(len = n, "received a response");
+    use super::Client;
 
-            let mut downstream_mutex = downstream_buff.lock().await;
-            let buffer: &mut Vec<u8> = downstream_mutex.as_mut();
-            self.stream.write_all(&buffer[0..n]).await?;
+    struct Echo;
+
+    impl AsyncRequestQueue for Echo {
+        async fn queue_request(&self, _frame: Frame, payload: Bytes, _max: usize) -> Outcome {
+            Ok(Bytes::copy_from_slice(&payload))
         }
+
+        async fn ready(&self) {}
+    }
+
+    /// Never answers, and tells when a request starts and when it's given up on.
+    #[derive(Default)]
+    struct Hang {
+        started: Notify,
+        dropped: Notify,
+    }
+
+    struct Dropped<'a>(&'a Notify);
+
+    impl Drop for Dropped<'_> {
+        fn drop(&mut self) {
+            self.0.notify_one();
+        }
+    }
+
+    impl AsyncRequestQueue for Hang {
+        async fn queue_request(&self, _frame: Frame, _payload: Bytes, _max: usize) -> Outcome {
+            let _dropped = Dropped(&self.dropped);
+            self.started.notify_one();
+            std::future::pending().await
+        }
+
+        async fn ready(&self) {}
+    }
+
+    fn service(codec: Framing) -> &'static Service {
+        Box::leak(Box::new(Service {
+            host: String::from("localhost"),
+            port: None,
+            max_msg_len: 32,
+            codec,
+            max_in_flight: 16,
+            tls: None,
+            socket_mode: None,
+        }))
+    }
+
+    #[tokio::test]
+    async fn requests_are_given_up_on_when_the_client_disconnects() -> io::Result<()> {
+        let queue: &'static Hang = Box::leak(Box::default());
+        let buffers: &'static BufferPool = Box::leak(Box::new(BufferPool::new()));
+        let (stream, mut client) = tokio::io::duplex(64);
+
+        let serving = tokio::spawn(async move {
+            let shutdown = CancellationToken::new();
+            let mut client = Client::new(
+                stream,
+                service(Framing::U16Be),
+                queue,
+                buffers,
+                shutdown,
+                None,
+            );
+            client.serve().await
+        });
+
+        client.write_all(&[0, 2, b'h', b'i']).await?;
+        queue.started.notified().await;
+        drop(client);
+
+        assert!(serving.await.unwrap().is_err());
+        tokio::time::timeout(Duration::from_secs(1), queue.dropped.notified())
+            .await
+            .expect("the request should be dropped");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_clients_dont_hold_buffers() -> io::Result<()> {
+        let service = service(Framing::U16Be);
+        let buffers: &'static BufferPool = Box::leak(Box::new(BufferPool::new()));
+        let shutdown = CancellationToken::new();
+
+        let mut streams = vec![];
+        for _ in 0..100 {
+            let (stream, client) = tokio::io::duplex(64);
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                let mut client = Client::new(stream, service, &Echo, buffers, shutdown, None);
+                client.serve().await
+            });
+            streams.push(client);
+        }
+
+        // Every client sends a request, one after the other, and then idles
+        for stream in &mut streams {
+            stream.write_all(&[0, 2, b'h', b'i']).await?;
+            let mut response = [0u8; 4];
+            stream.read_exact(&mut response).await?;
+            assert_eq!([0, 2, b'h', b'i'], response);
+        }
+
+        // Every request gave its buffer back before the next one was read, and
+        // the clients that wait for their next request don't take one
+        assert_eq!(1, buffers.allocated());
+
+        shutdown.cancel();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn l3_v2_clients_are_told_about_invalid_versions() -> io::Result<()> {
+        let buffers: &'static BufferPool = Box::leak(Box::new(BufferPool::new()));
+        let (stream, mut client) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            let shutdown = CancellationToken::new();
+            let service = service(Framing::L3V2);
+            let mut client = Client::new(stream, service, &Echo, buffers, shutdown, None);
+            client.serve().await
+        });
+
+        // The id and the length are where they are in a v2 header
+        let mut invalid = Frame::with_request_id(7, 2).as_bytes();
+        invalid[0] = 3;
+        client.write_all(&invalid).await?;
+        client.write_all(b"hi").await?;
+
+        let mut response = [0u8; HEADER_LEN + 1];
+        client.read_exact(&mut response).await?;
+        assert_eq!(Frame::error(7).as_bytes(), response[..HEADER_LEN]);
+        assert_eq!(ErrorCode::InvalidVersion as u8, response[HEADER_LEN]);
+
+        // The payload was skipped and the connection is still usable
+        client
+            .write_all(&Frame::with_request_id(8, 2).as_bytes())
+            .await?;
+        client.write_all(b"ok").await?;
+        let mut response = [0u8; HEADER_LEN + 2];
+        client.read_exact(&mut response).await?;
+        assert_eq!(
+            Frame::with_request_id(8, 2).as_bytes(),
+            response[..HEADER_LEN]
+        );
+        assert_eq!(b"ok", &response[HEADER_LEN..]);
+
+        Ok(())
     }
 }
@@ -1,50 +1,242 @@
-use std::io::{self, ErrorKind};
+use std::{
+    io::{self, ErrorKind},
+    sync::Arc,
+};
 
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{error, info, warn};
 
-use crate::{config::Config, downstream::client::Client, upstream::pool::AsyncRequestQueue};
+use crate::{
+    buffer::BufferPool,
+    config::Service,
+    downstream::client::Client,
+    stream::Listener,
+    tls::{self, Acceptor},
+    upstream::pool::AsyncRequestQueue,
+};
 
 pub struct Server<T>
 where
     T: AsyncRequestQueue + Send + Sync + 'static,
 {
-    config: &'static Config,
+    service: &'static Service,
     queue: &'static T,
+    buffers: &'static BufferPool,
+    shutdown: CancellationToken,
+    clients: TaskTracker,
+    /// Drops the clients that are still connected, once draining takes too long.
+    abort: CancellationToken,
 }
 
 impl<T> Server<T>
 where
     T: AsyncRequestQueue + Send + Sync,
 {
-    pub fn new(config: &'static Config, queue: &'static T) -> Self {
-        Server { config, queue }
+    pub fn new(
+        service: &'static Service,
+        queue: &'static T,
+        buffers: &'static BufferPool,
+        shutdown: CancellationToken,
+        clients: TaskTracker,
+        abort: CancellationToken,
+    ) -> Self {
+        Server {
+            service,
+            queue,
+            buffers,
+            shutdown,
+            clients,
+            abort,
+        }
     }
 
+    /// Accepts connections until `shutdown` is cancelled. The clients are
+    /// spawned on `clients` so they can be drained afterwards, and are
+    /// dropped along with their requests once `abort` is cancelled.
+    ///
+    /// Clients of a TLS listener are only spawned once they finish the handshake.
+    /// The socket file of a unix socket listener is removed once it returns.
     pub async fn start(&'static self) -> io::Result<()> {
-        info!("starting the downstream server");
+        let host = self.service.host.as_str();
+        let port = self.service.port;
+        info!(host, ?port, "starting the downstream server");
+
+        let tls = match &self.service.tls {
+            Some(config) => {
+                let tls = Arc::new(Acceptor::new(config)?);
+                let reloading = tls.clone();
+                let shutdown = self.shutdown.clone();
+                tokio::spawn(async move { reloading.reload_periodically(shutdown).await });
+                Some(tls)
+            }
+            None => None,
+        };
 
-        let listener = tokio::net::TcpListener::bind("localhost:8000").await?;
+        let listener = Listener::bind(self.service).await?;
         loop {
-            match listener.accept().await {
+            let accepted = tokio::select! {
+                _ = self.shutdown.cancelled() => {
+                    info!(host, port, "no longer accepting connections");
+                    return Ok(());
+                }
+                accepted = listener.accept() => accepted,
+            };
+
+            match accepted {
                 Err(e) => {
                     error!(err = ?e, "error accepting a connection")
                 }
                 Ok((stream, addr)) => {
                     info!(?addr, "new connection");
-                    tokio::spawn(async {
-                        let mut c = Client::new(stream, self.config, self.queue);
-                        match &c.serve().await {
-                            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
-                                info!("client disconnected");
+                    let tls = tls.clone();
+                    let abort = self.abort.clone();
+                    self.clients.spawn(async move {
+                        tokio::select! {
+                            _ = abort.cancelled() => {
+                                warn!(?addr, "the drain timeout elapsed, dropping the client");
                             }
-                            Err(e) => {
-                                warn!(err = e.kind().to_string(), "client error");
-                            }
-                            Ok(()) => {}
+                            _ = self.accept(stream, &addr, tls) => {}
                         }
                     });
                 }
             }
         }
     }
+
+    /// Serves a new connection, once it finished the TLS handshake and is
+    /// allowed on the listener if it's a TLS listener.
+    async fn accept<S>(&'static self, stream: S, addr: &str, tls: Option<Arc<Acceptor>>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Some(tls) = tls else {
+            return self.serve(stream, None).await;
+        };
+
+        let stream = match tls.accept(stream).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(?addr, err = %e, "TLS handshake failed");
+                return;
+            }
+        };
+
+        let names = tls::names(&stream);
+        let identity = names.first().cloned();
+        if !tls.allows(&names) {
+            warn!(?addr, identity, "client isn't allowed on this listener");
+            return;
+        }
+
+        info!(?addr, identity, "client connected over TLS");
+        self.serve(stream, identity).await
+    }
+
+    async fn serve<S>(&'static self, stream: S, identity: Option<String>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut c = Client::new(
+            stream,
+            self.service,
+            self.queue,
+            self.buffers,
+            self.shutdown.clone(),
+            identity,
+        );
+        match &c.serve().await {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                info!(identity = c.identity(), "client disconnected");
+            }
+            Err(e) => {
+                warn!(identity = c.identity(), err = %e, "client error");
+            }
+            Ok(()) => {}
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::{io::AsyncWriteExt, net::UnixStream, sync::Notify};
+    use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+    use crate::{
+        buffer::BufferPool,
+        codec::Framing,
+        config::Service,
+        frame::Frame,
+        upstream::pool::{AsyncRequestQueue, Outcome},
+    };
+
+    use super::Server;
+
+    /// Never answers, and tells when a request starts.
+    #[derive(Default)]
+    struct Hang {
+        started: Notify,
+    }
+
+    impl AsyncRequestQueue for Hang {
+        async fn queue_request(&self, _frame: Frame, _payload: Bytes, _max: usize) -> Outcome {
+            self.started.notify_one();
+            std::future::pending().await
+        }
+
+        async fn ready(&self) {}
+    }
+
+    #[tokio::test]
+    async fn clients_are_dropped_when_draining_takes_too_long() {
+        let path = std::env::temp_dir().join(format!("l3-server-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let service = Box::leak(Box::new(Service {
+            host: format!("unix:{path}"),
+            port: None,
+            max_msg_len: 32,
+            codec: Framing::U16Be,
+            max_in_flight: 1,
+            tls: None,
+            socket_mode: None,
+        }));
+
+        let queue: &'static Hang = Box::leak(Box::default());
+        let shutdown = CancellationToken::new();
+        let abort = CancellationToken::new();
+        let clients = TaskTracker::new();
+        let server = Box::leak(Box::new(Server::new(
+            service,
+            queue,
+            Box::leak(Box::new(BufferPool::new())),
+            shutdown.clone(),
+            clients.clone(),
+            abort.clone(),
+        )));
+        let serving = tokio::spawn(server.start());
+
+        let mut client = loop {
+            match UnixStream::connect(&path).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        client.write_all(&[0, 2, b'h', b'i']).await.unwrap();
+        queue.started.notified().await;
+
+        // The request never completes, so neither does the drain
+        shutdown.cancel();
+        serving.await.unwrap().unwrap();
+        clients.close();
+        let drain = tokio::time::timeout(Duration::from_millis(100), clients.wait());
+        assert!(drain.await.is_err());
+
+        abort.cancel();
+        tokio::time::timeout(Duration::from_secs(1), clients.wait())
+            .await
+            .expect("the clients should be dropped");
+    }
 }
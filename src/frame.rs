@@ -1,17 +1,84 @@
 use thiserror::Error;
 
-#[derive(Debug, Error)]
+pub const V1: u8 = 1;
+pub const V2: u8 = 2;
+
+pub const HEADER_LEN: usize = 8;
+
+/// Set on v2 responses whose payload is a single `ErrorCode` byte.
+pub const FLAG_ERROR: u8 = 0x01;
+
+/// Set on v2 requests that are safe to send more than once.
+pub const FLAG_IDEMPOTENT: u8 = 0x02;
+
+#[derive(Debug, Clone, Error)]
 pub enum FrameError {
-    #[error("invalid version {0} (expected 1)")]
+    #[error("invalid version {0} (expected 1 or 2)")]
     InvalidVersion(u8),
+    /// An l3_v2 header with another version. The request id and the length
+    /// are where they are in any v2 header, so the request can be failed on
+    /// its own.
+    #[error("invalid version {version} of request {request_id} (expected 2)")]
+    InvalidRequestVersion {
+        version: u8,
+        request_id: u16,
+        msg_len: u32,
+    },
     #[error("message length cannot be 0")]
     ZeroMessageLength,
+    #[error("message length does not fit in 32 bits")]
+    LengthOverflow,
+    #[error("message length {0} is greater than the maximum")]
+    MessageTooLarge(u32),
+    #[error("response for unknown request id {0:?}")]
+    UnknownRequest(Option<u16>),
+}
+
+/// Tells a v2 client why its request failed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// No upstream connection picked up the request in time.
+    QueueTimeout = 1,
+    /// The upstream connection failed before responding.
+    UpstreamUnavailable = 2,
+    /// The request is larger than `max_msg_len`.
+    MessageTooLarge = 3,
+    /// The request header has an unknown version. Only sent to l3_v2
+    /// clients, the other codecs close the connection instead.
+    InvalidVersion = 4,
+    /// The upstream responded with something that isn't a valid frame.
+    UpstreamProtocolError = 5,
+    /// The request header is malformed. Not sent by the built-in codecs,
+    /// which close the connection instead.
+    InvalidFrame = 6,
+    /// The upstream didn't respond within `response_timeout`.
+    UpstreamTimeout = 7,
+    /// The queue is full.
+    QueueFull = 8,
+    /// The circuit breaker of the upstream cluster is open.
+    CircuitOpen = 9,
+}
+
+impl From<&FrameError> for ErrorCode {
+    fn from(e: &FrameError) -> Self {
+        match e {
+            FrameError::InvalidVersion(_) | FrameError::InvalidRequestVersion { .. } => {
+                ErrorCode::InvalidVersion
+            }
+            FrameError::MessageTooLarge(_) => ErrorCode::MessageTooLarge,
+            FrameError::ZeroMessageLength
+            | FrameError::LengthOverflow
+            | FrameError::UnknownRequest(_) => ErrorCode::InvalidFrame,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Frame {
     version: u8,
-    // Placeholder for reserved bytes
+    // Placeholder for reserved bytes in v1. In v2 p1 holds the flags and
+    // p2-p3 hold the request id.
     p1: u8,
     p2: u8,
     p3: u8,
@@ -29,9 +96,26 @@ impl Frame {
         }
     }
 
+    pub fn with_request_id(request_id: u16, msg_len: u32) -> Self {
+        let [p2, p3] = request_id.to_le_bytes();
+        Frame {
+            version: V2,
+            p1: 0,
+            p2,
+            p3,
+            msg_len,
+        }
+    }
+
+    pub fn error(request_id: u16) -> Self {
+        let mut frame = Frame::with_request_id(request_id, 1);
+        frame.p1 = FLAG_ERROR;
+        frame
+    }
+
     pub fn from_bytes(buff: &[u8; 8]) -> Result<Self, FrameError> {
         let version = buff[0];
-        if version != 1 {
+        if version != V1 && version != V2 {
             return Err(FrameError::InvalidVersion(version));
         }
 
@@ -57,6 +141,30 @@ impl Frame {
         let [b1, b2, b3, b4] = self.msg_len.to_le_bytes();
         [self.version, self.p1, self.p2, self.p3, b1, b2, b3, b4]
     }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.version == V2 && self.p1 & FLAG_ERROR != 0
+    }
+
+    pub fn is_idempotent(&self) -> bool {
+        self.version == V2 && self.p1 & FLAG_IDEMPOTENT != 0
+    }
+
+    pub fn set_flags(&mut self, flags: u8) {
+        self.p1 |= flags;
+    }
+
+    /// The request id carried by v2 frames. v1 frames don't have one.
+    pub fn request_id(&self) -> Option<u16> {
+        match self.version {
+            V2 => Some(u16::from_le_bytes([self.p2, self.p3])),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -66,7 +174,7 @@ mod test {
     use super::Frame;
 
     #[test]
-    fn from_bytes_return_error_if_version_is_not_one() -> Result<(), FrameError> {
+    fn from_bytes_return_error_if_version_is_not_supported() -> Result<(), FrameError> {
         let b: [u8; 8] = [0x00, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
         let result = Frame::from_bytes(&b);
         match result.unwrap_err() {
@@ -90,6 +198,7 @@ mod test {
     }
 
     #[test]
+    #[allow(unnecessary_transmutes)]
     fn from_bytes_properly_constructs_a_frame() -> Result<(), FrameError> {
         let b: [u8; 8] = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
         let expected = Frame {
@@ -106,4 +215,39 @@ mod test {
         assert_eq!(expected, result);
         Ok(())
     }
+
+    #[test]
+    fn from_bytes_reads_the_request_id_of_a_v2_frame() -> Result<(), FrameError> {
+        let b: [u8; 8] = [0x02, 0x00, 0x34, 0x12, 0x05, 0x00, 0x00, 0x00];
+        let result = Frame::from_bytes(&b)?;
+
+        assert_eq!(Frame::with_request_id(0x1234, 5), result);
+        assert_eq!(Some(0x1234), result.request_id());
+        assert_eq!(b, result.as_bytes());
+        assert!(!result.is_error());
+        Ok(())
+    }
+
+    #[test]
+    fn error_frames_carry_the_request_id_and_the_error_flag() -> Result<(), FrameError> {
+        let b = Frame::error(0x1234).as_bytes();
+        assert_eq!([0x02, 0x01, 0x34, 0x12, 0x01, 0x00, 0x00, 0x00], b);
+
+        let result = Frame::from_bytes(&b)?;
+        assert!(result.is_error());
+        assert_eq!(Some(0x1234), result.request_id());
+        Ok(())
+    }
+
+    #[test]
+    fn only_v2_frames_can_be_idempotent() -> Result<(), FrameError> {
+        let b: [u8; 8] = [0x02, 0x02, 0x34, 0x12, 0x05, 0x00, 0x00, 0x00];
+        let result = Frame::from_bytes(&b)?;
+        assert!(result.is_idempotent());
+        assert!(!result.is_error());
+
+        let b: [u8; 8] = [0x01, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00];
+        assert!(!Frame::from_bytes(&b)?.is_idempotent());
+        Ok(())
+    }
 }
@@ -1,5 +1,9 @@
+pub mod buffer;
+pub mod codec;
 pub mod config;
 pub mod daemon;
 pub mod downstream;
 pub mod frame;
+pub mod stream;
+pub mod tls;
 pub mod upstream;
@@ -1,13 +1,17 @@
 use std::error::Error;
 
 use l3::{config::Config, daemon::Daemon};
-use tracing::info;
+use tracing::{info, warn};
 
+pub mod buffer;
 mod cli;
+pub mod codec;
 pub mod config;
 pub mod daemon;
 mod downstream;
 pub mod frame;
+mod stream;
+mod tls;
 pub mod upstream;
 
 #[tokio::main]
@@ -19,8 +23,47 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let conf: &'static mut Config = Box::leak(Box::new(Config::read_from_file(&args.config)?));
     info!(config = ?conf, "⚙️ loaded configuration");
 
-    let daemon = Daemon::new(conf);
+    let daemon = Daemon::new(conf)?;
+    let shutdown = daemon.shutdown_handle();
+    tokio::spawn(async move {
+        let mut signals = Signals::new();
+        signals.recv().await;
+        info!("🛑 shutting down, signal again to force exit");
+        shutdown.shutdown();
+
+        signals.recv().await;
+        warn!("forcing exit");
+        std::process::exit(1);
+    });
+
     daemon.run().await?;
 
     Ok(())
 }
+
+/// SIGINT and SIGTERM (on unix)
+struct Signals {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    fn new() -> Self {
+        Signals {
+            #[cfg(unix)]
+            terminate: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("failed to install the SIGTERM handler"),
+        }
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = self.terminate.recv() => {}
+        }
+
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
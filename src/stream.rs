@@ -0,0 +1,276 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::client::TlsStream;
+use tracing::warn;
+
+use crate::{
+    config::{self, Service},
+    tls::Connector,
+};
+
+/// A TCP or unix socket connection, to an upstream host over TLS if the
+/// cluster uses it.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Tls(Box<TlsStream<Stream>>),
+}
+
+/// Connects to `address`, and does the TLS handshake if `tls` is set.
+///
+/// The connection goes to `resolved` instead if the name of `address` was
+/// already resolved. The handshake still uses the name.
+pub async fn connect(
+    address: &str,
+    resolved: Option<SocketAddr>,
+    tls: Option<&Connector>,
+) -> io::Result<Stream> {
+    let stream = match (config::unix_socket(address), resolved) {
+        #[cfg(unix)]
+        (Some(path), _) => Stream::Unix(UnixStream::connect(path).await?),
+        #[cfg(not(unix))]
+        (Some(_), _) => return Err(unsupported()),
+        (None, Some(addr)) => Stream::Tcp(TcpStream::connect(addr).await?),
+        (None, None) => Stream::Tcp(TcpStream::connect(address).await?),
+    };
+
+    match tls {
+        Some(tls) => Ok(Stream::Tls(Box::new(tls.connect(address, stream).await?))),
+        None => Ok(stream),
+    }
+}
+
+/// Listens on a TCP or a unix socket.
+///
+/// The socket file of a unix socket is replaced if it's left over from an
+/// earlier run, and removed when the listener is dropped.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, String),
+}
+
+impl Listener {
+    pub async fn bind(service: &Service) -> io::Result<Self> {
+        match config::unix_socket(&service.host) {
+            #[cfg(unix)]
+            Some(path) => bind_unix(path, service.socket_mode).await,
+            #[cfg(not(unix))]
+            Some(_) => Err(unsupported()),
+            None => {
+                let port = service.port.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "tcp listeners need a port")
+                })?;
+                Ok(Listener::Tcp(
+                    TcpListener::bind((service.host.as_str(), port)).await?,
+                ))
+            }
+        }
+    }
+
+    /// A new connection, and the address of the peer to log.
+    pub async fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), addr.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                // Clients of a unix socket rarely bind to an address of their own
+                Ok((Stream::Unix(stream), format!("unix:{path}")))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn bind_unix(path: &str, mode: Option<u32>) -> io::Result<Listener> {
+    use std::{
+        fs,
+        os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        path::Path,
+    };
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{path} exists and isn't a socket"),
+            ));
+        }
+
+        // Nothing answers on a socket that's left over from an earlier run
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{path} is in use"),
+            ));
+        }
+
+        warn!(path, "removing a stale socket file");
+        fs::remove_file(path)?;
+    }
+
+    let Some(mode) = mode else {
+        return Ok(Listener::Unix(UnixListener::bind(path)?, path.to_owned()));
+    };
+
+    // The socket is bound in a directory that nobody else can get into and
+    // only moved into place once it has its permissions, so there is no
+    // moment where anyone can connect to it
+    let parent = Path::new(path)
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let dir = parent.join(format!(".l3-{}", std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let private = dir.join("s");
+    let listener = UnixListener::bind(&private).and_then(|listener| {
+        fs::set_permissions(&private, fs::Permissions::from_mode(mode))?;
+        fs::rename(&private, path)?;
+        Ok(listener)
+    });
+    if listener.is_err() {
+        let _ = fs::remove_file(&private);
+    }
+    if let Err(e) = fs::remove_dir(&dir) {
+        warn!(dir = %dir.display(), err = %e, "failed to remove the directory the socket was bound in");
+    }
+
+    Ok(Listener::Unix(listener?, path.to_owned()))
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            if let Err(e) = std::fs::remove_file(&*path) {
+                warn!(path, err = %e, "failed to remove the socket file");
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix sockets aren't supported on this platform",
+    )
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixListener,
+    };
+
+    use super::{connect, Listener};
+    use crate::{codec::Framing, config::Service};
+
+    fn service(path: &str) -> Service {
+        Service {
+            host: format!("unix:{path}"),
+            port: None,
+            max_msg_len: 32,
+            codec: Framing::L3,
+            max_in_flight: 1,
+            tls: None,
+            socket_mode: Some(0o600),
+        }
+    }
+
+    #[tokio::test]
+    async fn unix_listeners_clean_up_their_socket_file() {
+        let path = std::env::temp_dir().join(format!("l3-listener-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // Left over from a run that didn't shut down cleanly
+        drop(UnixListener::bind(path).unwrap());
+        assert!(fs::metadata(path).is_ok());
+
+        let listener = Listener::bind(&service(path)).await.unwrap();
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+
+        let address = format!("unix:{path}");
+        let (client, server) = tokio::join!(connect(&address, None, None), listener.accept());
+        let (mut client, mut server) = (client.unwrap(), server.unwrap().0);
+        client.write_all(b"PING").await.unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"PING", &buf);
+
+        // A second listener can't take the socket over
+        assert!(Listener::bind(&service(path)).await.is_err());
+
+        drop(listener);
+        assert!(fs::metadata(path).is_err());
+    }
+}
@@ -0,0 +1,701 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    client,
+    rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+        server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+        sign::CertifiedKey,
+        ClientConfig, DigitallySignedStruct, Error, RootCertStore, ServerConfig, SignatureScheme,
+    },
+    server::TlsStream,
+    TlsAcceptor, TlsConnector,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+use crate::config::{self, ServiceTls, UpstreamTls};
+
+/// Clients that don't finish the handshake in time are disconnected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Terminates TLS for a listener, and authenticates the clients if it
+/// requires client certificates.
+///
+/// The certificate is read again whenever its files change, which a
+/// `reload_periodically` task checks for. Connections that are already
+/// established keep the certificate they were set up with.
+pub struct Acceptor {
+    config: &'static ServiceTls,
+    acceptor: TlsAcceptor,
+    cert: Arc<ReloadingCert>,
+    provider: Arc<CryptoProvider>,
+    /// When the certificate and key files were last modified.
+    modified: Mutex<(SystemTime, SystemTime)>,
+}
+
+impl Acceptor {
+    pub fn new(config: &'static ServiceTls) -> io::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let modified = modified(config)?;
+        let cert = Arc::new(ReloadingCert(RwLock::new(Arc::new(load_cert(
+            config, &provider,
+        )?))));
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?;
+        let builder = match &config.client_ca {
+            Some(ca) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(roots(ca)?),
+                    provider.clone(),
+                )
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut server = builder.with_cert_resolver(cert.clone());
+        server.alpn_protocols = config.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+        Ok(Acceptor {
+            config,
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            cert,
+            provider,
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Does the handshake with a client that just connected.
+    pub async fn accept<T>(&self, stream: T) -> io::Result<TlsStream<T>>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))?
+    }
+
+    /// Whether a client with a certificate for `names` can use the listener.
+    /// It can't if any of its names is denied, and it can if any of them is
+    /// allowed. Clients without a name only can if there is no `allow` list.
+    pub fn allows(&self, names: &[String]) -> bool {
+        if names.iter().any(|name| self.config.deny.contains(name)) {
+            return false;
+        }
+
+        self.config.allow.is_empty() || names.iter().any(|name| self.config.allow.contains(name))
+    }
+
+    /// Checks the certificate files for changes every `reload_interval`
+    /// until `shutdown` is cancelled.
+    pub async fn reload_periodically(&self, shutdown: CancellationToken) {
+        let mut interval = tokio::time::interval(self.config.reload_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick is immediate and the certificate was just loaded
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = interval.tick() => {}
+            }
+
+            if let Err(e) = self.reload_if_changed() {
+                warn!(
+                    cert = self.config.cert,
+                    err = %e,
+                    "failed to reload the certificate, keeping the old one"
+                );
+            }
+        }
+    }
+
+    /// Reads the certificate again if its files were modified since the last
+    /// time. Returns whether it was reloaded.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let modified = modified(self.config)?;
+        if *self.modified.lock().unwrap() == modified {
+            return Ok(false);
+        }
+
+        let cert = load_cert(self.config, &self.provider)?;
+        *self.cert.0.write().unwrap() = Arc::new(cert);
+        *self.modified.lock().unwrap() = modified;
+        info!(cert = self.config.cert, "reloaded the certificate");
+
+        Ok(true)
+    }
+}
+
+/// The names of a client that presented a verified certificate: the DNS and
+/// URI subject alternative names of the certificate, then the common name of
+/// its subject. The first one is its identity in the logs.
+pub fn names<T>(stream: &TlsStream<T>) -> Vec<String> {
+    let Some(cert) = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|c| c.first())
+    else {
+        return vec![];
+    };
+    let Ok((_, cert)) = X509Certificate::from_der(cert) else {
+        return vec![];
+    };
+
+    let mut names = vec![];
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        names.extend(
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) | GeneralName::URI(name) => Some(name.to_string()),
+                    _ => None,
+                }),
+        );
+    }
+
+    let cn = cert.subject().iter_common_name().next();
+    names.extend(cn.and_then(|cn| cn.as_str().ok()).map(String::from));
+    names
+}
+
+/// Hands out the current certificate to every handshake.
+#[derive(Debug)]
+struct ReloadingCert(RwLock<Arc<CertifiedKey>>);
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().unwrap().clone())
+    }
+}
+
+/// Opens TLS connections to the hosts of a cluster.
+pub struct Connector {
+    connector: TlsConnector,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl Connector {
+    pub fn new(config: &UpstreamTls) -> io::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?;
+
+        let builder = match (&config.ca, config.insecure_skip_verify) {
+            (_, true) => {
+                warn!("the certificates of the upstream hosts aren't verified");
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(SkipVerification(provider)))
+            }
+            (Some(ca), false) => builder.with_root_certificates(roots(ca)?),
+            (None, false) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "upstream TLS needs a CA bundle to verify the hosts against",
+                ))
+            }
+        };
+
+        let client = match (&config.cert, &config.key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a client certificate needs both cert and key",
+                ))
+            }
+        };
+
+        let server_name = config
+            .server_name
+            .as_ref()
+            .map(|name| server_name(name))
+            .transpose()?;
+
+        Ok(Connector {
+            connector: TlsConnector::from(Arc::new(client)),
+            server_name,
+        })
+    }
+
+    /// Does the handshake with the host at `address` over `stream`.
+    pub async fn connect<T>(&self, address: &str, stream: T) -> io::Result<client::TlsStream<T>>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let name = match &self.server_name {
+            Some(name) => name.clone(),
+            None if config::unix_socket(address).is_some() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "hosts on unix sockets need a server_name for TLS",
+                ))
+            }
+            None => server_name(host_part(address))?,
+        };
+
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, self.connector.connect(name, stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))?
+    }
+}
+
+/// Accepts whatever certificate the host presents, as long as the handshake
+/// is signed with its key.
+#[derive(Debug)]
+struct SkipVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipVerification {
+    fn verify_server_cert(
+        &self,
+        _: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
+        _: &[u8],
+        _: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn server_name(name: &str) -> io::Result<ServerName<'static>> {
+    ServerName::try_from(name.to_owned())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// `example.com:443` -> `example.com`, `[::1]:443` -> `::1`
+fn host_part(address: &str) -> &str {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn modified(config: &ServiceTls) -> io::Result<(SystemTime, SystemTime)> {
+    Ok((
+        fs::metadata(&config.cert)?.modified()?,
+        fs::metadata(&config.key)?.modified()?,
+    ))
+}
+
+fn load_cert(config: &ServiceTls, provider: &CryptoProvider) -> io::Result<CertifiedKey> {
+    CertifiedKey::from_der(load_certs(&config.cert)?, load_key(&config.key)?, provider)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificates in {path}"),
+        ));
+    }
+
+    Ok(certs)
+}
+
+fn roots(path: &str) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+
+    Ok(roots)
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no private key in {path}"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use rcgen::CertifiedKey;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{
+        rustls::{
+            crypto::ring, pki_types::ServerName, server::WebPkiClientVerifier, ClientConfig,
+            RootCertStore, ServerConfig,
+        },
+        TlsAcceptor, TlsConnector,
+    };
+
+    use super::{host_part, load_certs, load_key, names, Acceptor, Connector};
+    use crate::config::{ServiceTls, UpstreamTls};
+
+    /// A self-signed certificate for `san` in `{name}-cert.pem` and `{name}-key.pem`.
+    fn write_cert(dir: &Path, name: &str, san: &str) -> CertifiedKey {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from(san)]).unwrap();
+        fs::write(dir.join(format!("{name}-cert.pem")), cert.cert.pem()).unwrap();
+        fs::write(
+            dir.join(format!("{name}-key.pem")),
+            cert.key_pair.serialize_pem(),
+        )
+        .unwrap();
+        cert
+    }
+
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("l3-{test}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn path(dir: &Path, file: &str) -> String {
+        dir.join(file).to_string_lossy().into_owned()
+    }
+
+    fn connector(cert: &CertifiedKey, alpn: &[u8]) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(
+            tokio_rustls::rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        config.alpn_protocols = vec![alpn.to_vec()];
+        TlsConnector::from(Arc::new(config))
+    }
+
+    /// Connects over an in-memory stream and echoes one message back.
+    async fn handshake(acceptor: &Acceptor, connector: TlsConnector) -> std::io::Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = async {
+            let mut stream = acceptor.accept(server).await?;
+            assert_eq!(Some(&b"l3"[..]), stream.get_ref().1.alpn_protocol());
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&buf).await?;
+            stream.flush().await
+        };
+        let client = async {
+            let name = ServerName::try_from("localhost").unwrap();
+            let mut stream = connector.connect(name, client).await?;
+            stream.write_all(b"PING").await?;
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            assert_eq!(b"PING", &buf);
+            Ok(())
+        };
+
+        tokio::try_join!(server, client).map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn picks_up_a_new_certificate_without_a_restart() {
+        let dir = temp_dir("tls-reload");
+        let old = write_cert(&dir, "server", "localhost");
+
+        let config: &'static ServiceTls = Box::leak(Box::new(ServiceTls {
+            cert: path(&dir, "server-cert.pem"),
+            key: path(&dir, "server-key.pem"),
+            alpn: vec![String::from("l3")],
+            client_ca: None,
+            allow: vec![],
+            deny: vec![],
+            reload_interval: Duration::from_secs(60),
+        }));
+        let acceptor = Acceptor::new(config).unwrap();
+
+        handshake(&acceptor, connector(&old, b"l3")).await.unwrap();
+        // ALPN was offered but not for a protocol the listener speaks
+        assert!(handshake(&acceptor, connector(&old, b"h2")).await.is_err());
+        assert!(!acceptor.reload_if_changed().unwrap());
+
+        // Some file systems only keep the modification time in seconds
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let new = write_cert(&dir, "server", "localhost");
+        assert!(acceptor.reload_if_changed().unwrap());
+
+        handshake(&acceptor, connector(&new, b"l3")).await.unwrap();
+        assert!(handshake(&acceptor, connector(&old, b"l3")).await.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A host that requires a client certificate signed by `client_ca`.
+    fn upstream(dir: &Path, client_ca: &CertifiedKey) -> TlsAcceptor {
+        let provider = Arc::new(ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        roots.add(client_ca.cert.der().clone()).unwrap();
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .unwrap();
+
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                load_certs(&path(dir, "upstream-cert.pem")).unwrap(),
+                load_key(&path(dir, "upstream-key.pem")).unwrap(),
+            )
+            .unwrap();
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    async fn connect(upstream: &TlsAcceptor, connector: &Connector) -> std::io::Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = async {
+            let mut stream = upstream.accept(server).await?;
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(b"PONG").await?;
+            stream.flush().await
+        };
+        let client = async {
+            let mut stream = connector.connect("localhost:4444", client).await?;
+            stream.write_all(b"PING").await?;
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            assert_eq!(b"PONG", &buf);
+            Ok(())
+        };
+
+        tokio::try_join!(server, client).map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn connects_to_upstreams_with_a_client_certificate() {
+        let dir = temp_dir("tls-upstream");
+        write_cert(&dir, "upstream", "localhost");
+        let client = write_cert(&dir, "client", "localhost");
+        write_cert(&dir, "other", "localhost");
+        let upstream = upstream(&dir, &client);
+
+        let config = UpstreamTls {
+            ca: Some(path(&dir, "upstream-cert.pem")),
+            cert: Some(path(&dir, "client-cert.pem")),
+            key: Some(path(&dir, "client-key.pem")),
+            server_name: None,
+            insecure_skip_verify: false,
+        };
+        connect(&upstream, &Connector::new(&config).unwrap())
+            .await
+            .unwrap();
+
+        // The host requires a client certificate
+        let anonymous = UpstreamTls {
+            cert: None,
+            key: None,
+            ..config
+        };
+        assert!(connect(&upstream, &Connector::new(&anonymous).unwrap())
+            .await
+            .is_err());
+
+        // The certificate of the host isn't signed by the CA
+        let untrusted = UpstreamTls {
+            ca: Some(path(&dir, "other-cert.pem")),
+            cert: Some(path(&dir, "client-cert.pem")),
+            key: Some(path(&dir, "client-key.pem")),
+            ..anonymous
+        };
+        assert!(connect(&upstream, &Connector::new(&untrusted).unwrap())
+            .await
+            .is_err());
+
+        let skip_verify = UpstreamTls {
+            insecure_skip_verify: true,
+            ..untrusted
+        };
+        connect(&upstream, &Connector::new(&skip_verify).unwrap())
+            .await
+            .unwrap();
+
+        // The certificate is for localhost
+        let wrong_name = UpstreamTls {
+            ca: Some(path(&dir, "upstream-cert.pem")),
+            server_name: Some(String::from("example.com")),
+            insecure_skip_verify: false,
+            ..skip_verify
+        };
+        assert!(connect(&upstream, &Connector::new(&wrong_name).unwrap())
+            .await
+            .is_err());
+
+        let no_ca = UpstreamTls {
+            ca: None,
+            server_name: None,
+            ..wrong_name
+        };
+        assert!(Connector::new(&no_ca).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn server_names_default_to_the_host_part_of_the_address() {
+        assert_eq!("example.com", host_part("example.com:443"));
+        assert_eq!("127.0.0.1", host_part("127.0.0.1:4444"));
+        assert_eq!("::1", host_part("[::1]:4444"));
+        assert_eq!("localhost", host_part("localhost"));
+    }
+
+    #[tokio::test]
+    async fn authenticates_clients_by_their_certificate() {
+        let dir = temp_dir("tls-clients");
+        write_cert(&dir, "server", "localhost");
+        write_cert(&dir, "client", "billing.internal");
+        write_cert(&dir, "other", "orders.internal");
+
+        let config: &'static ServiceTls = Box::leak(Box::new(ServiceTls {
+            cert: path(&dir, "server-cert.pem"),
+            key: path(&dir, "server-key.pem"),
+            alpn: vec![],
+            client_ca: Some(path(&dir, "client-cert.pem")),
+            allow: vec![],
+            deny: vec![],
+            reload_interval: Duration::from_secs(60),
+        }));
+        let acceptor = &Acceptor::new(config).unwrap();
+
+        let client = |name: Option<&str>| {
+            Connector::new(&UpstreamTls {
+                ca: Some(path(&dir, "server-cert.pem")),
+                cert: name.map(|name| path(&dir, &format!("{name}-cert.pem"))),
+                key: name.map(|name| path(&dir, &format!("{name}-key.pem"))),
+                server_name: None,
+                insecure_skip_verify: false,
+            })
+            .unwrap()
+        };
+        let connect = |connector: Connector| async move {
+            let (client, server) = tokio::io::duplex(4096);
+            let server = async {
+                let mut stream = acceptor.accept(server).await?;
+                stream.write_all(b"PONG").await?;
+                stream.flush().await?;
+                Ok(names(&stream))
+            };
+            let client = async {
+                let mut stream = connector.connect("localhost:8000", client).await?;
+                let mut buf = [0u8; 4];
+                stream.read_exact(&mut buf).await
+            };
+
+            tokio::try_join!(server, client).map(|(names, _)| names)
+        };
+
+        let names = connect(client(Some("client"))).await.unwrap();
+        assert_eq!(vec!["billing.internal", "rcgen self signed cert"], names);
+
+        // No certificate, or one that isn't signed by the client CA
+        assert!(connect(client(None)).await.is_err());
+        assert!(connect(client(Some("other"))).await.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deny_rules_win_over_allow_rules() {
+        let dir = temp_dir("tls-rules");
+        write_cert(&dir, "server", "localhost");
+
+        let acceptor = |allow: &[&str], deny: &[&str]| {
+            let config = Box::leak(Box::new(ServiceTls {
+                cert: path(&dir, "server-cert.pem"),
+                key: path(&dir, "server-key.pem"),
+                alpn: vec![],
+                client_ca: Some(path(&dir, "server-cert.pem")),
+                allow: allow.iter().map(|s| s.to_string()).collect(),
+                deny: deny.iter().map(|s| s.to_string()).collect(),
+                reload_interval: Duration::from_secs(60),
+            }));
+            Acceptor::new(config).unwrap()
+        };
+
+        let names = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let anyone = acceptor(&[], &[]);
+        assert!(anyone.allows(&names(&["billing.internal"])));
+        assert!(anyone.allows(&[]));
+
+        let some = acceptor(
+            &["billing.internal", "orders.internal"],
+            &["orders.internal"],
+        );
+        assert!(some.allows(&names(&["billing.internal"])));
+        assert!(!some.allows(&names(&["orders.internal"])));
+        assert!(!some.allows(&names(&["search.internal"])));
+        assert!(!some.allows(&[]));
+        // Every name counts, not just the first one
+        assert!(some.allows(&names(&["search.internal", "billing.internal"])));
+        assert!(!some.allows(&names(&["billing.internal", "orders.internal"])));
+
+        let all_but = acceptor(&[], &["orders.internal"]);
+        assert!(all_but.allows(&names(&["billing.internal"])));
+        assert!(!all_but.allows(&names(&["billing.internal", "orders.internal"])));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
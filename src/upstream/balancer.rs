@@ -0,0 +1,601 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    config::{self, HashKey, Upstream},
+    frame::Frame,
+};
+
+use super::host::Host;
+
+/// Decides which host a request goes to.
+pub trait Balancer: Send + Sync {
+    /// The key that routes the request, for balancers that keep requests
+    /// with the same key on the same host. It's worked out once per request,
+    /// however many hosts it's sent to.
+    fn key(&self, _frame: &Frame, _payload: &[u8]) -> Option<u64> {
+        None
+    }
+
+    /// Picks a host for the request with `key` out of the ones that accept
+    /// requests, skipping the ones it was already `tried` on. Returns None if
+    /// there aren't any.
+    fn pick(&self, hosts: &[Host], tried: &[usize], key: Option<u64>) -> Option<usize>;
+}
+
+fn eligible(hosts: &[Host], tried: &[usize], i: usize) -> bool {
+    hosts[i].accepts_requests() && !tried.contains(&i)
+}
+
+pub fn new(config: &'static Upstream, hosts: &[Host]) -> Box<dyn Balancer> {
+    match &config.balancer {
+        config::Balancer::RoundRobin => Box::new(RoundRobin::new(hosts.len())),
+        config::Balancer::LeastOutstanding => Box::new(LeastOutstanding::default()),
+        config::Balancer::PowerOfTwoChoices => Box::new(PowerOfTwoChoices::new(|host: &Host| {
+            host.outstanding() as f64
+        })),
+        config::Balancer::PeakEwma { .. } => Box::new(PowerOfTwoChoices::new(|host: &Host| {
+            host.latency.cost(host.outstanding())
+        })),
+        config::Balancer::ConsistentHash(c) => Box::new(ConsistentHash::new(c, hosts)),
+    }
+}
+
+/// Smooth weighted round robin, as done by nginx. Hosts get requests in
+/// proportion to their weight, and the requests of heavier hosts are spread
+/// out instead of being sent in bursts.
+pub struct RoundRobin {
+    current: Mutex<Vec<i64>>,
+}
+
+impl RoundRobin {
+    pub fn new(hosts: usize) -> Self {
+        RoundRobin {
+            current: Mutex::new(vec![0; hosts]),
+        }
+    }
+}
+
+impl Balancer for RoundRobin {
+    fn pick(&self, hosts: &[Host], tried: &[usize], _key: Option<u64>) -> Option<usize> {
+        let mut current = self.current.lock().unwrap();
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+
+        for (i, host) in hosts.iter().enumerate() {
+            if !eligible(hosts, tried, i) {
+                continue;
+            }
+
+            current[i] += host.weight as i64;
+            total += host.weight as i64;
+            if best.is_none_or(|b| current[i] > current[b]) {
+                best = Some(i);
+            }
+        }
+
+        let best = best?;
+        current[best] -= total;
+        Some(best)
+    }
+}
+
+/// The host with the fewest outstanding requests relative to its weight.
+/// Ties are broken by starting the scan at a different host every time.
+#[derive(Default)]
+pub struct LeastOutstanding {
+    next: AtomicUsize,
+}
+
+impl Balancer for LeastOutstanding {
+    fn pick(&self, hosts: &[Host], tried: &[usize], _key: Option<u64>) -> Option<usize> {
+        let offset = self.next.fetch_add(1, Ordering::Relaxed);
+        let load = |i: usize| hosts[i].outstanding() as f64 / hosts[i].weight as f64;
+
+        (0..hosts.len())
+            .map(|n| (offset + n) % hosts.len())
+            .filter(|i| eligible(hosts, tried, *i))
+            .min_by(|a, b| load(*a).total_cmp(&load(*b)))
+    }
+}
+
+/// Picks two random hosts and sends the request to the one with the lower
+/// cost relative to its weight. This avoids the herding of always picking
+/// the best host, which every balancer would agree on, without having to
+/// look at all of them.
+pub struct PowerOfTwoChoices<F> {
+    cost: F,
+    random: Random,
+}
+
+impl<F> PowerOfTwoChoices<F>
+where
+    F: Fn(&Host) -> f64 + Send + Sync,
+{
+    pub fn new(cost: F) -> Self {
+        PowerOfTwoChoices {
+            cost,
+            random: Random::new(),
+        }
+    }
+}
+
+impl<F> Balancer for PowerOfTwoChoices<F>
+where
+    F: Fn(&Host) -> f64 + Send + Sync,
+{
+    fn pick(&self, hosts: &[Host], tried: &[usize], _key: Option<u64>) -> Option<usize> {
+        let eligible: Vec<usize> = (0..hosts.len())
+            .filter(|i| eligible(hosts, tried, *i))
+            .collect();
+
+        let n = eligible.len() as u64;
+        if n < 2 {
+            return eligible.first().copied();
+        }
+
+        let a = (self.random.next() % n) as usize;
+        let mut b = (self.random.next() % (n - 1)) as usize;
+        if b >= a {
+            b += 1;
+        }
+
+        let cost = |i: usize| (self.cost)(&hosts[i]) / hosts[i].weight as f64;
+        let (a, b) = (eligible[a], eligible[b]);
+        match cost(a) <= cost(b) {
+            true => Some(a),
+            false => Some(b),
+        }
+    }
+}
+
+/// The peak EWMA of the response latency of a host, as in Finagle.
+///
+/// Latencies above the average replace it right away, lower ones are blended
+/// in with a weight that depends on how long ago the last one was recorded.
+/// This makes a host that slows down lose its requests quickly, while one
+/// that recovers only gets them back gradually. Failures count as responses
+/// that are much slower than the average, and the average of a host that
+/// doesn't get any responses drifts back to `INITIAL_LATENCY`.
+pub struct Latency {
+    decay: Duration,
+    // The average in nanoseconds and when it was last updated, None until
+    // the first response
+    state: Mutex<Option<(f64, Instant)>>,
+}
+
+/// What a host is expected to take before it responded to anything.
+const INITIAL_LATENCY: Duration = Duration::from_millis(100);
+
+/// A failed or timed out request counts as a response that took this many
+/// times the current average.
+const FAILURE_PENALTY: f64 = 10.0;
+
+impl Latency {
+    pub fn new(decay: Duration) -> Self {
+        Latency {
+            decay,
+            state: Mutex::new(None),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let sample = latency.as_nanos() as f64;
+        self.update(|_| sample);
+    }
+
+    pub fn record_failure(&self) {
+        self.update(|expected| expected * FAILURE_PENALTY);
+    }
+
+    /// Blends in the sample that `sample` makes out of the expected latency.
+    fn update(&self, sample: impl FnOnce(f64) -> f64) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let sample = sample(self.expected(*state, now));
+
+        let average = match *state {
+            Some((average, updated_at)) if sample <= average => {
+                let w = self.weight(now.duration_since(updated_at));
+                average * w + sample * (1.0 - w)
+            }
+            // The first response replaces the initial latency
+            _ => sample,
+        };
+
+        *state = Some((average, now));
+    }
+
+    /// The average, drifting back to the initial latency while nothing is
+    /// recorded.
+    fn expected(&self, state: Option<(f64, Instant)>, now: Instant) -> f64 {
+        let initial = INITIAL_LATENCY.as_nanos() as f64;
+        match state {
+            None => initial,
+            Some((average, updated_at)) => {
+                let w = self.weight(now.duration_since(updated_at));
+                average * w + initial * (1.0 - w)
+            }
+        }
+    }
+
+    /// How much of the average is left after `elapsed`.
+    fn weight(&self, elapsed: Duration) -> f64 {
+        (-elapsed.as_secs_f64() / self.decay.as_secs_f64()).exp()
+    }
+
+    /// The expected latency of a new request, given the ones ahead of it.
+    pub fn cost(&self, outstanding: usize) -> f64 {
+        let state = *self.state.lock().unwrap();
+        self.expected(state, Instant::now()) * (outstanding + 1) as f64
+    }
+}
+
+/// A splitmix64 generator, good enough to pick hosts with.
+pub(super) struct Random {
+    state: AtomicU64,
+}
+
+impl Random {
+    pub(super) fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+
+        Random {
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    pub(super) fn next(&self) -> u64 {
+        mix(self.state.fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed))
+    }
+}
+
+/// Points on the ring per unit of weight.
+const POINTS_PER_WEIGHT: u32 = 100;
+/// Heavier hosts don't get any more points than this, so that a huge weight
+/// can't make the ring take up gigabytes. That's a weight of about 10000.
+const MAX_POINTS: u32 = 1 << 20;
+
+/// Ring hash with bounded loads. Each host owns points on the ring in
+/// proportion to its weight, and a key goes to the host of the first point
+/// after the hash of the key. Adding or removing a host only moves the keys
+/// of the points it owns.
+///
+/// A host that has more than its share of the outstanding requests (scaled
+/// by `max_load_percent`) is skipped, and so are the hosts that don't accept
+/// requests. Their keys go to the next host on the ring.
+pub struct ConsistentHash {
+    config: &'static config::ConsistentHash,
+    ring: Vec<(u64, usize)>,
+    total_weight: u64,
+    // Spreads the requests that don't have a key
+    counter: AtomicU64,
+}
+
+impl ConsistentHash {
+    pub fn new(config: &'static config::ConsistentHash, hosts: &[Host]) -> Self {
+        let mut ring = vec![];
+        let mut point_name = String::new();
+        for (i, host) in hosts.iter().enumerate() {
+            let points = host
+                .weight
+                .saturating_mul(POINTS_PER_WEIGHT)
+                .min(MAX_POINTS);
+            for point in 0..points {
+                point_name.clear();
+                // Writing to a String can't fail
+                let _ = write!(point_name, "{}-{}", host.address, point);
+                ring.push((hash(point_name.as_bytes()), i));
+            }
+        }
+        ring.sort_unstable();
+
+        ConsistentHash {
+            config,
+            ring,
+            total_weight: hosts.iter().map(|h| h.weight as u64).sum(),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Hashes the part of the request that the config points at.
+    fn hash_key(&self, frame: &Frame, payload: &[u8]) -> u64 {
+        let key = match &self.config.key {
+            HashKey::Range { start, end } => payload.get(*start..(*end).min(payload.len())),
+            HashKey::Delimiter(delimiter) => {
+                let delimiter = delimiter.as_bytes();
+                let end = match delimiter.is_empty() {
+                    true => None,
+                    false => payload
+                        .windows(delimiter.len())
+                        .position(|w| w == delimiter),
+                };
+                Some(&payload[..end.unwrap_or(payload.len())])
+            }
+            HashKey::RequestId => match frame.request_id() {
+                Some(id) => return hash(&id.to_le_bytes()),
+                None => None,
+            },
+        };
+
+        match key {
+            Some(key) => hash(key),
+            None => self.unkeyed(),
+        }
+    }
+
+    /// Spreads the requests that don't have a key evenly.
+    fn unkeyed(&self) -> u64 {
+        hash(&self.counter.fetch_add(1, Ordering::Relaxed).to_le_bytes())
+    }
+}
+
+impl Balancer for ConsistentHash {
+    fn key(&self, frame: &Frame, payload: &[u8]) -> Option<u64> {
+        Some(self.hash_key(frame, payload))
+    }
+
+    fn pick(&self, hosts: &[Host], tried: &[usize], key: Option<u64>) -> Option<usize> {
+        if self.ring.is_empty() {
+            return None;
+        }
+
+        // The new request counts too
+        let outstanding = hosts.iter().map(|h| h.outstanding()).sum::<usize>() as u64 + 1;
+        let max_load = self.config.max_load_percent as u64;
+        let has_room = |host: &Host| {
+            // capacity = outstanding * (weight / total_weight) * max_load_percent / 100, rounded up
+            let capacity =
+                (outstanding * host.weight as u64 * max_load).div_ceil(self.total_weight * 100);
+            (host.outstanding() as u64) < capacity
+        };
+
+        let hash = key.unwrap_or_else(|| self.unkeyed());
+        let start = self.ring.partition_point(|(point, _)| *point < hash);
+        let mut fallback = None;
+
+        for n in 0..self.ring.len() {
+            let (_, i) = self.ring[(start + n) % self.ring.len()];
+            if !eligible(hosts, tried, i) {
+                continue;
+            }
+
+            if has_room(&hosts[i]) {
+                return Some(i);
+            }
+
+            fallback = fallback.or(Some(i));
+        }
+
+        fallback
+    }
+}
+
+/// FNV-1a followed by the splitmix64 finalizer. Hashes have to be the same
+/// across processes for load balancers that share a set of hosts to agree on
+/// where a key goes, which rules out the randomly seeded std hasher.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+
+    mix(h)
+}
+
+/// The splitmix64 finalizer.
+fn mix(mut h: u64) -> u64 {
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58476d1ce4e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        config::{self, HashKey, Upstream, UpstreamHost},
+        frame::{Frame, V1},
+        upstream::host::Host,
+    };
+
+    use super::{
+        Balancer, ConsistentHash, Latency, LeastOutstanding, PowerOfTwoChoices, RoundRobin,
+        MAX_POINTS, POINTS_PER_WEIGHT,
+    };
+
+    fn hosts(weights: &[u32]) -> Vec<Host> {
+        let config: &'static Upstream = Box::leak(Box::default());
+
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| {
+                let host = Box::leak(Box::new(UpstreamHost {
+                    addr: format!("host{i}"),
+                    weight: *weight,
+                    connections: None,
+                }));
+                let host = Host::new(host, config);
+                host.connected();
+                host
+            })
+            .collect()
+    }
+
+    fn consistent_hash(key: HashKey, hosts: &[Host]) -> ConsistentHash {
+        let config = Box::leak(Box::new(config::ConsistentHash {
+            key,
+            max_load_percent: 125,
+        }));
+
+        ConsistentHash::new(config, hosts)
+    }
+
+    #[test]
+    fn hosts_are_picked_in_proportion_to_their_weight() {
+        let hosts = hosts(&[5, 1, 1]);
+        let balancer = RoundRobin::new(hosts.len());
+
+        let picks: Vec<usize> = (0..7)
+            .map(|_| balancer.pick(&hosts, &[], None).unwrap())
+            .collect();
+        assert_eq!(vec![0, 0, 1, 0, 2, 0, 0], picks);
+    }
+
+    #[test]
+    fn hosts_that_dont_accept_requests_are_skipped() {
+        let hosts = hosts(&[1, 1]);
+        let balancer = RoundRobin::new(hosts.len());
+
+        hosts[0].health.set_healthy(false);
+        assert_eq!(Some(1), balancer.pick(&hosts, &[], None));
+        assert_eq!(Some(1), balancer.pick(&hosts, &[], None));
+
+        hosts[1].disconnected();
+        assert_eq!(None, balancer.pick(&hosts, &[], None));
+    }
+
+    #[test]
+    fn keys_stick_to_their_host() {
+        let hosts = hosts(&[1, 1, 1, 1]);
+        let balancer = consistent_hash(HashKey::Delimiter(String::from(":")), &hosts);
+        let frame = Frame::new(V1, 1);
+
+        let key = |k: &str| {
+            let key = balancer.key(&frame, k.as_bytes());
+            balancer.pick(&hosts, &[], key).unwrap()
+        };
+        assert_eq!(key("user42:get"), key("user42:set"));
+
+        // Only the keys of the unavailable host move
+        let before: Vec<usize> = (0..100).map(|k| key(&format!("{k}:"))).collect();
+        hosts[2].health.set_healthy(false);
+        let after: Vec<usize> = (0..100).map(|k| key(&format!("{k}:"))).collect();
+
+        for (b, a) in before.iter().zip(&after) {
+            match b {
+                2 => assert_ne!(2, *a),
+                _ => assert_eq!(b, a),
+            }
+        }
+    }
+
+    #[test]
+    fn keys_spill_over_from_hosts_with_too_much_load() {
+        let hosts = hosts(&[1, 1]);
+        let balancer = consistent_hash(HashKey::Range { start: 0, end: 4 }, &hosts);
+        let frame = Frame::new(V1, 1);
+
+        let key = balancer.key(&frame, b"key1");
+        let first = balancer.pick(&hosts, &[], key).unwrap();
+        for _ in 0..4 {
+            hosts[first].begin_request();
+        }
+
+        // 5 outstanding * 1/2 * 125% rounds up to a capacity of 4
+        assert_ne!(first, balancer.pick(&hosts, &[], key).unwrap());
+
+        // 2 outstanding * 1/2 * 125% rounds up to a capacity of 2
+        for _ in 0..3 {
+            hosts[first].end_request();
+        }
+        assert_eq!(first, balancer.pick(&hosts, &[], key).unwrap());
+    }
+
+    #[test]
+    fn huge_weights_dont_overflow_the_ring() {
+        let hosts = hosts(&[u32::MAX, 1]);
+        let balancer = consistent_hash(HashKey::RequestId, &hosts);
+        assert_eq!(
+            (MAX_POINTS + POINTS_PER_WEIGHT) as usize,
+            balancer.ring.len()
+        );
+    }
+
+    #[test]
+    fn least_outstanding_accounts_for_the_weight() {
+        let hosts = hosts(&[2, 1]);
+        let balancer = LeastOutstanding::default();
+
+        hosts[0].begin_request();
+        assert_eq!(Some(1), balancer.pick(&hosts, &[], None));
+
+        hosts[1].begin_request();
+        assert_eq!(Some(0), balancer.pick(&hosts, &[], None));
+    }
+
+    #[test]
+    fn power_of_two_choices_never_picks_the_worst_host() {
+        let hosts = hosts(&[1, 1, 1]);
+        let balancer = PowerOfTwoChoices::new(|host: &Host| host.outstanding() as f64);
+
+        for _ in 0..3 {
+            hosts[1].begin_request();
+        }
+        hosts[2].begin_request();
+
+        for _ in 0..100 {
+            assert_ne!(Some(1), balancer.pick(&hosts, &[], None));
+        }
+
+        hosts[0].health.set_healthy(false);
+        hosts[2].health.set_healthy(false);
+        assert_eq!(Some(1), balancer.pick(&hosts, &[], None));
+    }
+
+    /// Whether `cost` is within 1% of `expected`.
+    fn about(expected: Duration, cost: f64) -> bool {
+        let expected = expected.as_nanos() as f64;
+        (cost - expected).abs() < expected / 100.0
+    }
+
+    #[test]
+    fn peak_ewma_jumps_up_and_decays_down() {
+        let ms = Duration::from_millis;
+        let latency = Latency::new(Duration::from_secs(60));
+        // Hosts that didn't respond yet aren't free
+        assert!(about(ms(100), latency.cost(0)));
+
+        latency.record(ms(10));
+        assert!(about(ms(10), latency.cost(0)));
+
+        latency.record(ms(20));
+        assert!(about(ms(20), latency.cost(0)));
+        assert!(about(ms(40), latency.cost(1)));
+
+        // Failures count as much slower responses
+        latency.record_failure();
+        assert!(about(ms(200), latency.cost(0)));
+
+        // A few decays later the old peak is all but forgotten
+        let latency = Latency::new(Duration::from_millis(1));
+        latency.record(ms(20));
+        std::thread::sleep(ms(10));
+        latency.record(ms(10));
+        let (average, _) = latency.state.lock().unwrap().unwrap();
+        assert!(about(ms(10), average));
+    }
+
+    #[test]
+    fn peak_ewma_of_idle_hosts_drifts_back_to_the_initial_latency() {
+        let latency = Latency::new(Duration::from_millis(1));
+        latency.record(Duration::from_secs(1));
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert!(about(Duration::from_millis(100), latency.cost(0)));
+    }
+}
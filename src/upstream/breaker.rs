@@ -0,0 +1,278 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use super::pool::RequestError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Requests go through.
+    Closed,
+    /// Requests fail right away.
+    Open,
+    /// A few trial requests go through to see if the failures are over.
+    HalfOpen,
+}
+
+/// A circuit breaker. It opens after `threshold` consecutive failures and
+/// stays open for `open_time`. Then it lets `trials` requests through, and
+/// closes if they all succeed or opens again as soon as one of them fails.
+pub struct Breaker {
+    /// The host the breaker is for, or the cluster.
+    name: &'static str,
+    threshold: u32,
+    open_time: Duration,
+    trials: u32,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    state: State,
+    failures: u32,
+    opened_at: Instant,
+    /// Trial requests that were let through in the half-open state.
+    started: u32,
+    succeeded: u32,
+}
+
+/// A request that was let through. Dropping it without recording how it went
+/// gives the trial back, for requests that were given up on.
+pub struct Permit<'a> {
+    breaker: &'a Breaker,
+    recorded: bool,
+}
+
+impl Breaker {
+    pub fn new(name: &'static str, threshold: u32, open_time: Duration, trials: u32) -> Self {
+        Breaker {
+            name,
+            threshold,
+            open_time,
+            trials: trials.max(1),
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                failures: 0,
+                opened_at: Instant::now(),
+                started: 0,
+                succeeded: 0,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Whether a request would be let through right now.
+    pub fn allows(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::Closed => true,
+            State::Open => inner.opened_at.elapsed() >= self.open_time,
+            State::HalfOpen => inner.started < self.trials,
+        }
+    }
+
+    /// Lets a request through, unless the circuit is open or all the trials
+    /// are already in flight.
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> Option<Permit<'_>> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::Closed => {}
+            State::Open if now.saturating_duration_since(inner.opened_at) >= self.open_time => {
+                info!(name = self.name, "circuit breaker is half-open");
+                inner.state = State::HalfOpen;
+                inner.started = 1;
+                inner.succeeded = 0;
+            }
+            State::HalfOpen if inner.started < self.trials => inner.started += 1,
+            State::Open | State::HalfOpen => return None,
+        }
+
+        Some(Permit {
+            breaker: self,
+            recorded: false,
+        })
+    }
+
+    fn record(&self, success: bool, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        match (inner.state, success) {
+            (State::Closed, true) => inner.failures = 0,
+            (State::Closed, false) => {
+                inner.failures += 1;
+                if inner.failures >= self.threshold {
+                    warn!(
+                        name = self.name,
+                        failures = inner.failures,
+                        open_time = ?self.open_time,
+                        "circuit breaker opened"
+                    );
+                    inner.open(now);
+                }
+            }
+            (State::HalfOpen, true) => {
+                inner.succeeded += 1;
+                if inner.succeeded >= self.trials {
+                    info!(name = self.name, "circuit breaker closed");
+                    inner.state = State::Closed;
+                    inner.failures = 0;
+                }
+            }
+            (State::HalfOpen, false) => {
+                warn!(
+                    name = self.name,
+                    "a trial request failed, circuit breaker opened again"
+                );
+                inner.open(now);
+            }
+            // A request that was let through before the circuit opened
+            (State::Open, _) => {}
+        }
+    }
+
+    fn release(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == State::HalfOpen {
+            inner.started = inner.started.saturating_sub(1);
+        }
+    }
+}
+
+impl Inner {
+    fn open(&mut self, now: Instant) {
+        self.state = State::Open;
+        self.opened_at = now;
+    }
+}
+
+impl Permit<'_> {
+    /// Records the outcome of the request. Only the failures that point at
+    /// the upstream count against the circuit.
+    pub fn record<T>(self, outcome: &Result<T, RequestError>) {
+        self.record_at(outcome, Instant::now());
+    }
+
+    fn record_at<T>(mut self, outcome: &Result<T, RequestError>, now: Instant) {
+        self.recorded = true;
+        match outcome {
+            Ok(_) => self.breaker.record(true, now),
+            Err(e) if counts_as_failure(e) => self.breaker.record(false, now),
+            Err(_) => self.breaker.release(),
+        }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.release();
+        }
+    }
+}
+
+/// Whether the upstream is to blame: it couldn't be reached, the connection
+/// failed, or it responded too late or with garbage. Load on the load balancer
+/// itself, like requests shed from the queue, doesn't count.
+fn counts_as_failure(err: &RequestError) -> bool {
+    match err {
+        RequestError::Upstream { .. }
+        | RequestError::NotSent { .. }
+        | RequestError::ResponseTimeout { .. }
+        | RequestError::Frame { .. } => true,
+        RequestError::QueueTimeout
+        | RequestError::HostUnavailable { .. }
+        | RequestError::QueueFull
+        | RequestError::NoUpstream
+        | RequestError::CircuitOpen
+        | RequestError::QueueClosed
+        | RequestError::Interrupted => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::upstream::pool::RequestError;
+
+    use super::{Breaker, State};
+
+    fn timed_out() -> Result<(), RequestError> {
+        Err(RequestError::ResponseTimeout {
+            address: Box::leak(Box::new(String::from("localhost:4444"))),
+            timeout: Duration::from_secs(1),
+        })
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures_and_closes_after_the_trials() {
+        let breaker = Breaker::new("localhost:4444", 3, Duration::from_secs(10), 2);
+        let failure = timed_out();
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            breaker
+                .try_acquire_at(now)
+                .unwrap()
+                .record_at(&failure, now);
+        }
+        breaker.try_acquire_at(now).unwrap().record_at(&Ok(()), now);
+        for _ in 0..3 {
+            breaker
+                .try_acquire_at(now)
+                .unwrap()
+                .record_at(&failure, now);
+        }
+        assert_eq!(State::Open, breaker.state());
+        assert!(breaker.try_acquire_at(now).is_none());
+
+        // Only as many trials as configured, a dropped one is given back
+        let now = now + Duration::from_secs(11);
+        let first = breaker.try_acquire_at(now).unwrap();
+        drop(breaker.try_acquire_at(now).unwrap());
+        let second = breaker.try_acquire_at(now).unwrap();
+        assert!(breaker.try_acquire_at(now).is_none());
+        assert_eq!(State::HalfOpen, breaker.state());
+
+        first.record_at(&Ok(()), now);
+        second.record_at(&Ok(()), now);
+        assert_eq!(State::Closed, breaker.state());
+    }
+
+    #[test]
+    fn a_failed_trial_opens_the_circuit_again() {
+        let breaker = Breaker::new("localhost:4444", 1, Duration::from_secs(10), 1);
+        let now = Instant::now();
+        // Not the fault of the upstream
+        for failure in [RequestError::NoUpstream, RequestError::QueueTimeout] {
+            breaker
+                .try_acquire_at(now)
+                .unwrap()
+                .record_at(&Err::<(), _>(failure), now);
+        }
+        assert_eq!(State::Closed, breaker.state());
+
+        let failure = timed_out();
+        breaker
+            .try_acquire_at(now)
+            .unwrap()
+            .record_at(&failure, now);
+        assert_eq!(State::Open, breaker.state());
+
+        let now = now + Duration::from_secs(10);
+        breaker
+            .try_acquire_at(now)
+            .unwrap()
+            .record_at(&failure, now);
+        assert_eq!(State::Open, breaker.state());
+        assert!(breaker.try_acquire_at(now).is_none());
+    }
+}
@@ -1,37 +1,200 @@
-use std::{io, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
+use bytes::Bytes;
+use thiserror::Error;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
 };
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, warn};
 
-use crate::frame::Frame;
+use crate::{
+    buffer::BufferPool,
+    codec::{self, Codec, MAX_HEADER_LEN},
+    config::Upstream,
+    frame::{Frame, FrameError, V1},
+    stream::{self, Stream},
+    tls::Connector,
+};
 
-use super::pool::Request;
+use super::{
+    host::Host,
+    pool::{Request, RequestError},
+};
 
 pub struct Connection<T>
 where
     T: AsyncReadExt + AsyncWriteExt + Unpin,
 {
-    address: &'static String,
-    buffer_size: usize,
+    host: &'static Host,
+    config: &'static Upstream,
+    buffers: &'static BufferPool,
     stream: T,
-    queue: async_channel::Receiver<Request>,
+    closed: CancellationToken,
+}
+
+/// Requests that were written to the upstream and are waiting for a response.
+///
+/// v1 frames don't carry a request id, so up to `pipeline_depth` of them are
+/// in flight at a time and the upstream has to respond to them in the order
+/// they were sent. v2 requests are matched by an id that is unique per
+/// connection, which lets the upstream respond to them in any order. Up to
+/// `max_in_flight` of them are in flight at a time, so there is always an id
+/// to spare. Every
+/// request is sent as v2 if the upstream codec carries request ids, and as v1
+/// otherwise, whatever the client sent.
+#[derive(Default)]
+struct InFlight {
+    next_id: u16,
+    v1: VecDeque<Sent>,
+    v2: HashMap<u16, Sent>,
+    /// Notified whenever the last request in flight is removed.
+    emptied: Arc<Notify>,
+}
+
+struct Sent {
+    req: Request,
+    at: Instant,
+    /// Whether the upstream could have gotten the whole request. It's set
+    /// before the request is written, so a response or a broken connection
+    /// can't beat it, and cleared if the write fails. The upstream can't have
+    /// acted on a request that it only got part of.
+    written: bool,
+    /// Frees up room for another request on the connection once dropped.
+    _slot: OwnedSemaphorePermit,
+}
+
+impl Sent {
+    fn new(req: Request, slot: OwnedSemaphorePermit) -> Self {
+        Sent {
+            req,
+            at: Instant::now(),
+            written: true,
+            _slot: slot,
+        }
+    }
+
+    /// Hands the response to whoever queued the request and records the
+    /// result against the host.
+    fn complete(self, host: &'static Host, result: Result<Bytes, &io::Error>) {
+        let outcome = match result {
+            Ok(response) => {
+                let latency = self.at.elapsed();
+                host.stats.record_response(latency);
+                host.latency.record(latency);
+                Ok(response)
+            }
+            Err(e) => {
+                host.stats.record_failure();
+                host.latency.record_failure();
+                match self.written {
+                    true => Err(request_error(host.address, e)),
+                    false => Err(RequestError::NotSent {
+                        address: host.address,
+                        source: io::Error::new(e.kind(), e.to_string()),
+                    }),
+                }
+            }
+        };
+
+        // Err here means that the receiver is already deallocated
+        let _ = self.req.done.send(outcome);
+    }
+}
+
+impl InFlight {
+    fn oldest(&self) -> Option<Instant> {
+        self.v1.iter().chain(self.v2.values()).map(|s| s.at).min()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.v1.is_empty() && self.v2.is_empty()
+    }
+
+    /// Removes the oldest v1 request, or the v2 request with `id`.
+    fn remove(&mut self, id: Option<u16>) -> Option<Sent> {
+        let removed = match id {
+            None => self.v1.pop_front(),
+            Some(id) => self.v2.remove(&id),
+        };
+        self.removed();
+
+        removed
+    }
+
+    fn removed(&self) {
+        if self.is_empty() {
+            // Keeps the permit if nobody is waiting yet
+            self.emptied.notify_one();
+        }
+    }
+
+    /// Removes the requests that were sent before `sent_before`.
+    fn remove_expired(&mut self, sent_before: Instant) -> Vec<Sent> {
+        // The v1 requests were sent in order
+        let v1_expired = self.v1.partition_point(|s| s.at <= sent_before);
+        let mut expired: Vec<Sent> = self.v1.drain(..v1_expired).collect();
+
+        let ids: Vec<u16> = self
+            .v2
+            .iter()
+            .filter(|(_, s)| s.at <= sent_before)
+            .map(|(id, _)| *id)
+            .collect();
+        expired.extend(ids.iter().filter_map(|id| self.v2.remove(id)));
+        self.removed();
+
+        expired
+    }
+
+    fn insert_v2(&mut self, sent: Sent) -> u16 {
+        // Ends, since no more than max_in_flight ids are taken
+        while self.v2.contains_key(&self.next_id) {
+            self.next_id = self.next_id.wrapping_add(1);
+        }
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.v2.insert(id, sent);
+
+        id
+    }
+
+    fn fail_all(&mut self, host: &'static Host, err: &io::Error) {
+        let failed = self.v1.drain(..).chain(self.v2.drain().map(|(_, s)| s));
+        for sent in failed {
+            sent.complete(host, Err(err));
+        }
+        self.removed();
+    }
 }
 
-impl Connection<TcpStream> {
+impl Connection<Stream> {
+    /// Connects to `addr` if the name of the host was resolved, to its address
+    /// otherwise.
     pub async fn connect(
-        address: &'static String,
-        buffer_size: usize,
-        queue: async_channel::Receiver<Request>,
+        host: &'static Host,
+        addr: Option<SocketAddr>,
+        config: &'static Upstream,
+        tls: Option<&Connector>,
+        buffers: &'static BufferPool,
+        closed: CancellationToken,
     ) -> io::Result<Self> {
-        let stream = TcpStream::connect(&address).await?;
+        let stream = stream::connect(host.address, addr, tls).await?;
         let con = Connection {
-            address,
-            buffer_size,
+            host,
+            config,
+            buffers,
             stream,
-            queue,
+            closed,
         };
 
         Ok(con)
@@ -42,81 +205,729 @@ impl<T> Connection<T>
 where
     T: AsyncReadExt + AsyncWriteExt + Unpin,
 {
+    /// Sends the queued requests of the host until the pool is closed.
+    ///
+    /// A connection that is closed while the pool isn't, because its address
+    /// was removed from the name of the host, is drained: it stops taking
+    /// requests and waits for the responses to what's in flight first.
     pub async fn serve(&mut self) -> io::Result<()> {
-        // TODO: Read this from config
-        const QUEUE_TIMEOUT_THRESHOLD: Duration = Duration::from_millis(4);
-        loop {
-            match self.queue.recv().await {
-                Err(e) => {
-                    // TODO: Does this error happen only if the channel is closed? Overall need better error handling here...
-                    warn!(err = ?e, addr=self.address, "queue receive failure");
-                    return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
-                }
-                Ok(req) if req.queued_at.elapsed() > QUEUE_TIMEOUT_THRESHOLD => {
-                    warn!("request timed out in queue");
-                    let _ = req.done.send(-2); // Nothing to do if the channel is closed
-                }
-                Ok(req) => {
-                    let mut mut_guard = req.buff.lock().await;
-                    let buf: &mut Vec<u8> = mut_guard.as_mut();
-                    debug!(buf=?buf[0..req.msg_len], "picked up from queue");
-
-                    if let Err(e) = self.stream.write_all(&buf[0..req.msg_len]).await {
-                        // Err here means that the receiver is already deallocated
-                        let _ = req.done.send(-1);
-                        return Err(e);
-                    }
+        let in_flight = Mutex::new(InFlight::default());
+        let codec = self.config.codec.codec();
+        let slots = match codec.multiplexed() {
+            true => self.config.max_in_flight,
+            false => self.config.pipeline_depth,
+        };
+        let slots = Arc::new(Semaphore::new(slots.max(1)));
+        let (reader, writer) = tokio::io::split(&mut self.stream);
+        let timeout = self.config.response_timeout;
+        let host = self.host;
+        let drain_timeout = self.config.dns.as_ref().map(|dns| dns.drain_timeout);
 
-                    if let Err(e) = self.stream.read_exact(&mut buf[0..8]).await {
-                        // Err here means that the receiver is already deallocated
-                        let _ = req.done.send(-1);
-                        return Err(e);
-                    }
+        let sending = async {
+            send_requests(host, codec, &self.closed, writer, &in_flight, &slots).await?;
+            match drain_timeout {
+                Some(timeout) if !host.queue.is_closed() => drain(host, timeout, &in_flight).await,
+                _ => {}
+            }
+            Ok(())
+        };
+
+        let result = tokio::select! {
+            r = sending => r,
+            r = receive_responses(self.host, codec, self.buffers, timeout, reader, &in_flight) => r,
+            r = expire_requests(self.host, timeout, &in_flight) => r,
+        };
+
+        // Nothing that is still in flight is going to get a response on this connection
+        if let Err(e) = &result {
+            in_flight.lock().unwrap().fail_all(self.host, e);
+            return result;
+        }
+
+        // The pool is closed, let the upstream know that we are done
+        self.stream.shutdown().await
+    }
+}
+
+async fn send_requests<T>(
+    host: &'static Host,
+    codec: &'static dyn Codec,
+    closed: &CancellationToken,
+    mut writer: WriteHalf<T>,
+    in_flight: &Mutex<InFlight>,
+    slots: &Arc<Semaphore>,
+) -> io::Result<()>
+where
+    T: AsyncWrite,
+{
+    let address = host.address;
+    let mut header = Vec::with_capacity(MAX_HEADER_LEN);
+    loop {
+        if !host.health.is_available() {
+            debug!(
+                addr = address,
+                "upstream is unavailable, waiting for it to recover"
+            );
+            // New requests go to other hosts, the queued ones would only time out
+            host.fail_queued();
+
+            // The queue won't tell us that it's closed while we aren't reading from it
+            tokio::select! {
+                _ = closed.cancelled() => return Ok(()),
+                _ = host.health.wait_for(true) => {}
+            }
+        }
+
+        // Requests stay in the queue while the connection has no room for them, so
+        // they still time out there and the other connections can pick them up.
+        // The semaphore is never closed.
+        let slot = tokio::select! {
+            biased;
+            _ = closed.cancelled(), if !host.queue.is_closed() => return Ok(()),
+            _ = host.health.wait_for(false) => continue,
+            slot = slots.clone().acquire_owned() => slot.unwrap(),
+        };
+
+        // Dropping a pending recv doesn't lose any requests
+        let received = tokio::select! {
+            biased;
+            // The requests are left to the other connections of the host
+            _ = closed.cancelled(), if !host.queue.is_closed() => return Ok(()),
+            _ = host.health.wait_for(false) => continue,
+            received = host.queue.pop() => received,
+        };
+
+        match received {
+            None => {
+                // The queue is closed and empty, the pool is shutting down
+                debug!(addr = address, "request queue is closed");
+                return Ok(());
+            }
+            Some(req) => {
+                let payload = req.payload.clone();
+                let msg_len = req.frame.msg_len as usize;
+
+                // The request has to be registered before it's written, otherwise the
+                // response could arrive before we know who it belongs to.
+                let sent = Sent::new(req, slot);
+                let frame = if codec.multiplexed() {
+                    let id = in_flight.lock().unwrap().insert_v2(sent);
+                    Frame::with_request_id(id, msg_len as u32)
+                } else {
+                    in_flight.lock().unwrap().v1.push_back(sent);
+                    Frame::new(V1, msg_len as u32)
+                };
+
+                debug!(?payload, ?frame, "picked up from queue");
 
-                    let frame = match Frame::from_bytes(
-                        &buf[0..8]
-                            .try_into()
-                            .expect("couldn't convert buffer into [u8;8]"),
-                    ) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            // TODO: need to handle the FrameError
-                            // Err here means that the receiver is already deallocated
-                            let _ = req.done.send(-1);
-                            return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
-                        }
+                header.clear();
+                codec.encode(&frame, &mut header);
+                let written = async {
+                    writer.write_all(&header).await?;
+                    writer.write_all(&payload).await?;
+                    // TLS streams hold on to what's written until they're flushed
+                    writer.flush().await
+                }
+                .await;
+
+                if written.is_err() {
+                    let mut in_flight = in_flight.lock().unwrap();
+                    let sent = match frame.request_id() {
+                        Some(id) => in_flight.v2.get_mut(&id),
+                        // Requests are written one at a time, so it's the last one
+                        None => in_flight.v1.back_mut(),
                     };
-                    debug!(frame=?frame, "received from from upstream");
-
-                    if frame.msg_len as usize > self.buffer_size {
-                        warn!(
-                            frame.msg_len,
-                            self.buffer_size, "payload size is greater than the maximum"
-                        );
-
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "payload size is greater than the maximum",
-                        ));
+                    if let Some(sent) = sent {
+                        sent.written = false;
                     }
+                }
+                written?;
+            }
+        }
+    }
+}
 
-                    if let Err(e) = self
-                        .stream
-                        .read_exact(&mut buf[0..frame.msg_len as usize])
-                        .await
-                    {
-                        // Err here means that the receiver is already deallocated
-                        let _ = req.done.send(-1);
-                        return Err(e);
-                    }
+/// Waits for the responses to the requests in flight, and fails the ones that
+/// don't get one within `timeout`.
+async fn drain(host: &'static Host, timeout: Duration, in_flight: &Mutex<InFlight>) {
+    let drained = async {
+        loop {
+            let emptied = {
+                let in_flight = in_flight.lock().unwrap();
+                if in_flight.is_empty() {
+                    return;
+                }
+                in_flight.emptied.clone()
+            };
+            // A leftover permit from an earlier request only means another check
+            emptied.notified().await;
+        }
+    };
+
+    if tokio::time::timeout(timeout, drained).await.is_err() {
+        warn!(
+            addr = host.address,
+            ?timeout,
+            "draining the connection timed out"
+        );
+        let err = io::Error::new(io::ErrorKind::TimedOut, "the connection was drained");
+        in_flight.lock().unwrap().fail_all(host, &err);
+    }
+}
 
-                    // Err here means that the receiver is already deallocated
-                    let _ = req.done.send(frame.msg_len as i64); // u32 can fit in an i64
+async fn receive_responses<T>(
+    host: &'static Host,
+    codec: &'static dyn Codec,
+    buffers: &BufferPool,
+    timeout: Option<Duration>,
+    mut reader: ReadHalf<T>,
+    in_flight: &Mutex<InFlight>,
+) -> io::Result<()>
+where
+    T: AsyncRead,
+{
+    // Protocol errors are reported as an InvalidData FrameError, the framing is
+    // out of sync after any of them so the connection can't be reused.
+    loop {
+        let frame = codec::read_frame(codec, &mut reader)
+            .await?
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        debug!(frame=?frame, "received from from upstream");
+
+        let req = in_flight.lock().unwrap().remove(frame.request_id());
+
+        let Some(sent) = req else {
+            warn!(?frame, "received a response for an unknown request");
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                FrameError::UnknownRequest(frame.request_id()),
+            ));
+        };
+
+        // The limit is max_msg_len of the listener the request came from
+        if frame.msg_len as usize > sent.req.max_response_len {
+            warn!(
+                frame.msg_len,
+                max_msg_len = sent.req.max_response_len,
+                "payload size is greater than the maximum"
+            );
 
-                    // mut_guard unlock happens here
+            // Only this request fails, the payload is skipped to get to the next frame
+            let (mut payload, mut sink) =
+                ((&mut reader).take(frame.msg_len as u64), tokio::io::sink());
+            let skip = tokio::io::copy(&mut payload, &mut sink);
+            match before_deadline(sent.at, timeout, skip).await {
+                Ok(n) if n == frame.msg_len as u64 => {
+                    let e = io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        FrameError::MessageTooLarge(frame.msg_len),
+                    );
+                    sent.complete(host, Err(&e));
+                }
+                Ok(_) => {
+                    let e = io::Error::from(io::ErrorKind::UnexpectedEof);
+                    sent.complete(host, Err(&e));
+                    return Err(e);
+                }
+                Err(e) => {
+                    sent.complete(host, Err(&e));
+                    return Err(e);
                 }
             }
-            // TODO: add a branch for cancellation
+        } else {
+            // A stalled upstream is bound by the same deadline as the header
+            let read = buffers.read(&mut reader, frame.msg_len as usize);
+            let response = match before_deadline(sent.at, timeout, read).await {
+                Ok(response) => response,
+                Err(e) => {
+                    sent.complete(host, Err(&e));
+                    return Err(e);
+                }
+            };
+
+            sent.complete(host, Ok(response.freeze()));
         }
     }
 }
+
+/// Fails `read` once the response to a request that was sent at `sent_at` is
+/// overdue.
+async fn before_deadline<T>(
+    sent_at: Instant,
+    timeout: Option<Duration>,
+    read: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout_at((sent_at + timeout).into(), read)
+            .await
+            .unwrap_or_else(|_| Err(response_timeout(timeout))),
+        None => read.await,
+    }
+}
+
+/// Fails the requests that don't get a response within `timeout`.
+///
+/// The connection is reset as soon as one of them expires. The upstream may
+/// still respond to it, and there would be no telling which request that
+/// response belongs to.
+async fn expire_requests(
+    host: &'static Host,
+    timeout: Option<Duration>,
+    in_flight: &Mutex<InFlight>,
+) -> io::Result<()> {
+    let Some(timeout) = timeout else {
+        return std::future::pending().await;
+    };
+
+    loop {
+        let oldest = in_flight.lock().unwrap().oldest();
+        // Anything sent while sleeping expires after the next wake up
+        if oldest.is_none_or(|at| at.elapsed() < timeout) {
+            let wake_at = oldest.unwrap_or_else(Instant::now) + timeout;
+            tokio::time::sleep_until(wake_at.into()).await;
+            continue;
+        }
+
+        let expired = in_flight
+            .lock()
+            .unwrap()
+            .remove_expired(Instant::now() - timeout);
+        warn!(
+            addr = host.address,
+            requests = expired.len(),
+            ?timeout,
+            "upstream didn't respond in time, resetting the connection"
+        );
+
+        let e = response_timeout(timeout);
+        for sent in expired {
+            sent.complete(host, Err(&e));
+        }
+
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "resetting the connection after a response timeout",
+        ));
+    }
+}
+
+/// Marks an io::Error as a response timeout, see `request_error`.
+#[derive(Debug, Error)]
+#[error("no response within {0:?}")]
+struct ResponseTimeout(Duration);
+
+fn response_timeout(timeout: Duration) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, ResponseTimeout(timeout))
+}
+
+fn request_error(address: &'static String, err: &io::Error) -> RequestError {
+    let source = err.get_ref();
+    if let Some(e) = source.and_then(|e| e.downcast_ref::<FrameError>()) {
+        return RequestError::Frame {
+            address,
+            source: e.clone(),
+        };
+    }
+
+    if let Some(ResponseTimeout(timeout)) = source.and_then(|e| e.downcast_ref()) {
+        return RequestError::ResponseTimeout {
+            address,
+            timeout: *timeout,
+        };
+    }
+
+    RequestError::Upstream {
+        address,
+        source: io::Error::new(err.kind(), err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+        sync::oneshot,
+    };
+    use tokio_util::sync::CancellationToken;
+
+    use bytes::Bytes;
+
+    use crate::{
+        buffer::BufferPool,
+        codec::Framing,
+        config::{Dns, Upstream, UpstreamHost},
+        frame::{Frame, FrameError, V1},
+        upstream::{
+            host::Host,
+            pool::{Outcome, Request, RequestError},
+        },
+    };
+
+    use super::{request_error, response_timeout, Connection};
+
+    #[test]
+    fn request_error_tells_frame_errors_apart_from_io_errors() {
+        let address: &'static String = Box::leak(Box::new(String::from("localhost:4444")));
+
+        let err = io::Error::new(io::ErrorKind::InvalidData, FrameError::InvalidVersion(7));
+        match request_error(address, &err) {
+            RequestError::Frame {
+                address,
+                source: FrameError::InvalidVersion(7),
+            } if address == "localhost:4444" => {}
+            e => panic!("invalid error {e:?}"),
+        }
+
+        let err = io::Error::from(io::ErrorKind::ConnectionReset);
+        match request_error(address, &err) {
+            RequestError::Upstream { address, source }
+                if address == "localhost:4444"
+                    && source.kind() == io::ErrorKind::ConnectionReset => {}
+            e => panic!("invalid error {e:?}"),
+        }
+    }
+
+    #[test]
+    fn request_error_tells_timeouts_apart_from_io_errors() {
+        let address: &'static String = Box::leak(Box::new(String::from("localhost:4444")));
+
+        match request_error(address, &response_timeout(Duration::from_secs(1))) {
+            RequestError::ResponseTimeout { timeout, .. } if timeout == Duration::from_secs(1) => {}
+            e => panic!("invalid error {e:?}"),
+        }
+
+        let err = io::Error::from(io::ErrorKind::TimedOut);
+        match request_error(address, &err) {
+            RequestError::Upstream { .. } => {}
+            e => panic!("invalid error {e:?}"),
+        }
+    }
+
+    fn connection(
+        codec: Framing,
+        pipeline_depth: usize,
+        response_timeout: Option<Duration>,
+    ) -> (Connection<DuplexStream>, &'static Host, DuplexStream) {
+        connection_with(Upstream {
+            codec,
+            pipeline_depth,
+            response_timeout,
+            ..Default::default()
+        })
+    }
+
+    fn connection_with(
+        config: Upstream,
+    ) -> (Connection<DuplexStream>, &'static Host, DuplexStream) {
+        let (stream, upstream) = tokio::io::duplex(64);
+        let (conn, host) = connection_on(config, stream);
+
+        (conn, host, upstream)
+    }
+
+    fn connection_on<T>(config: Upstream, stream: T) -> (Connection<T>, &'static Host)
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let config: &'static Upstream = Box::leak(Box::new(config));
+        let host = Box::leak(Box::new(UpstreamHost::from(String::from("localhost:4444"))));
+        let host: &'static Host = Box::leak(Box::new(Host::new(host, config)));
+
+        let conn = Connection {
+            host,
+            config,
+            buffers: Box::leak(Box::new(BufferPool::new())),
+            stream,
+            closed: CancellationToken::new(),
+        };
+
+        (conn, host)
+    }
+
+    /// Never has anything to read and fails every write.
+    struct BrokenPipe;
+
+    impl AsyncRead for BrokenPipe {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for BrokenPipe {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn request(payload: &'static [u8]) -> (Request, oneshot::Receiver<Outcome>) {
+        let (done, outcome) = oneshot::channel();
+        let req = Request {
+            frame: Frame::new(V1, payload.len() as u32),
+            max_response_len: 4,
+            payload: Bytes::from_static(payload),
+            done,
+            queued_at: Instant::now(),
+        };
+
+        (req, outcome)
+    }
+
+    #[tokio::test]
+    async fn requests_fail_and_the_connection_resets_when_the_upstream_stalls() {
+        // The other end is kept open, but never responds
+        let (mut conn, host, _upstream) =
+            connection(Framing::L3, 1, Some(Duration::from_millis(50)));
+
+        let (req, outcome) = request(&[1; 4]);
+        host.queue.push(req).await.unwrap();
+
+        let err = conn.serve().await.unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+
+        match outcome.await.unwrap() {
+            Err(RequestError::ResponseTimeout { .. }) => {}
+            o => panic!("invalid outcome {o:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn pipelined_responses_are_matched_in_order() {
+        let (mut conn, host, mut upstream) = connection(Framing::U16Be, 2, None);
+
+        let (first, first_outcome) = request(&[1, 1]);
+        let (second, second_outcome) = request(&[2, 2]);
+        host.queue.push(first).await.unwrap();
+        host.queue.push(second).await.unwrap();
+
+        let respond = async {
+            // Both requests are written before there is any response
+            let mut requests = [0u8; 8];
+            upstream.read_exact(&mut requests).await.unwrap();
+            assert_eq!([0, 2, 1, 1, 0, 2, 2, 2], requests);
+
+            upstream.write_all(&[0, 1, b'a', 0, 1, b'b']).await.unwrap();
+            (first_outcome.await.unwrap(), second_outcome.await.unwrap())
+        };
+
+        let (first, second) = tokio::select! {
+            r = conn.serve() => panic!("the connection closed: {r:?}"),
+            outcomes = respond => outcomes,
+        };
+        assert_eq!(&b"a"[..], first.unwrap());
+        assert_eq!(&b"b"[..], second.unwrap());
+    }
+
+    #[tokio::test]
+    async fn requests_stay_queued_while_the_pipeline_is_full() {
+        let (mut conn, host, mut upstream) = connection(Framing::U16Be, 1, None);
+
+        let (first, first_outcome) = request(&[1, 1]);
+        let (second, second_outcome) = request(&[2, 2]);
+        host.queue.push(first).await.unwrap();
+        host.queue.push(second).await.unwrap();
+
+        let respond = async {
+            let mut request = [0u8; 4];
+            upstream.read_exact(&mut request).await.unwrap();
+
+            // Left to the other connections of the host in the meantime
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(1, host.queue.len());
+
+            upstream.write_all(&[0, 1, b'a']).await.unwrap();
+            (first_outcome.await.unwrap(), second_outcome.await.unwrap())
+        };
+
+        let (first, second) = tokio::select! {
+            r = conn.serve() => panic!("the connection closed: {r:?}"),
+            outcomes = respond => outcomes,
+        };
+        assert_eq!(&b"a"[..], first.unwrap());
+
+        // It waited for longer than queue_timeout
+        match second {
+            Err(RequestError::QueueTimeout) => {}
+            o => panic!("invalid outcome {o:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn oversize_responses_only_fail_their_request() {
+        let (mut conn, host, mut upstream) = connection(Framing::U16Be, 2, None);
+
+        let (first, first_outcome) = request(&[1, 1]);
+        let (second, second_outcome) = request(&[2, 2]);
+        host.queue.push(first).await.unwrap();
+        host.queue.push(second).await.unwrap();
+
+        let respond = async {
+            let mut requests = [0u8; 8];
+            upstream.read_exact(&mut requests).await.unwrap();
+
+            // Longer than max_response_len, followed by a valid response
+            upstream
+                .write_all(&[0, 5, b'a', b'a', b'a', b'a', b'a', 0, 1, b'b'])
+                .await
+                .unwrap();
+            (first_outcome.await.unwrap(), second_outcome.await.unwrap())
+        };
+
+        let (first, second) = tokio::select! {
+            r = conn.serve() => panic!("the connection closed: {r:?}"),
+            outcomes = respond => outcomes,
+        };
+        match first {
+            Err(RequestError::Frame {
+                source: FrameError::MessageTooLarge(5),
+                ..
+            }) => {}
+            r => panic!("unexpected outcome {r:?}"),
+        }
+        assert_eq!(&b"b"[..], second.unwrap());
+    }
+
+    #[tokio::test]
+    async fn requests_with_ids_are_limited_to_max_in_flight() {
+        let (mut conn, host, mut upstream) = connection_with(Upstream {
+            codec: Framing::L3V2,
+            max_in_flight: 2,
+            queue_timeout: Duration::from_secs(1),
+            ..Default::default()
+        });
+
+        let mut outcomes = vec![];
+        for payload in [&[1, 1], &[2, 2], &[3, 3]] {
+            let (req, outcome) = request(payload);
+            host.queue.push(req).await.unwrap();
+            outcomes.push(outcome);
+        }
+
+        let respond = async {
+            let mut requests = [0u8; 20];
+            upstream.read_exact(&mut requests).await.unwrap();
+
+            // The third request waits for one of the first two to complete
+            let mut third = [0u8; 10];
+            let read =
+                tokio::time::timeout(Duration::from_millis(50), upstream.read_exact(&mut third));
+            assert!(read.await.is_err());
+            assert_eq!(1, host.queue.len());
+
+            let header: [u8; 8] = requests[..8].try_into().unwrap();
+            let id = Frame::from_bytes(&header).unwrap().request_id().unwrap();
+            let response = Frame::with_request_id(id, 1).as_bytes();
+            upstream.write_all(&response).await.unwrap();
+            upstream.write_all(b"a").await.unwrap();
+
+            upstream.read_exact(&mut third).await.unwrap();
+            assert_eq!([3, 3], third[8..]);
+            outcomes.remove(0).await.unwrap()
+        };
+
+        let first = tokio::select! {
+            r = conn.serve() => panic!("the connection closed: {r:?}"),
+            outcome = respond => outcome,
+        };
+        assert_eq!(&b"a"[..], first.unwrap());
+    }
+
+    #[tokio::test]
+    async fn only_requests_that_failed_to_be_written_are_not_sent() {
+        // The upstream goes away after it got the whole request
+        let (mut conn, host, mut upstream) = connection(Framing::U16Be, 1, None);
+        let (req, outcome) = request(&[1, 1]);
+        host.queue.push(req).await.unwrap();
+
+        let hang_up = async move {
+            let mut sent = [0u8; 4];
+            upstream.read_exact(&mut sent).await.unwrap();
+        };
+        let (served, _) = tokio::join!(conn.serve(), hang_up);
+        assert!(served.is_err());
+        match outcome.await.unwrap() {
+            Err(RequestError::Upstream { .. }) => {}
+            o => panic!("invalid outcome {o:?}"),
+        }
+
+        // The request never makes it to the upstream
+        let (mut conn, host) = connection_on(
+            Upstream {
+                codec: Framing::U16Be,
+                ..Default::default()
+            },
+            BrokenPipe,
+        );
+        let (req, outcome) = request(&[1, 1]);
+        host.queue.push(req).await.unwrap();
+
+        assert!(conn.serve().await.is_err());
+        match outcome.await.unwrap() {
+            Err(RequestError::NotSent { .. }) => {}
+            o => panic!("invalid outcome {o:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn removed_addresses_are_drained() {
+        let (mut conn, host, mut upstream) = connection_with(Upstream {
+            codec: Framing::U16Be,
+            pipeline_depth: 2,
+            dns: Some(Dns {
+                min_ttl: Duration::from_secs(1),
+                max_ttl: Duration::from_secs(300),
+                drain_timeout: Duration::from_millis(100),
+            }),
+            ..Default::default()
+        });
+        let closed = conn.closed.clone();
+
+        let (first, first_outcome) = request(&[1, 1]);
+        host.queue.push(first).await.unwrap();
+
+        let respond = async {
+            let mut sent = [0u8; 4];
+            upstream.read_exact(&mut sent).await.unwrap();
+            closed.cancel();
+
+            // Requests that are queued while draining are left to the other connections
+            let (second, _second_outcome) = request(&[2, 2]);
+            host.queue.push(second).await.unwrap();
+
+            upstream.write_all(&[0, 1, b'a']).await.unwrap();
+            first_outcome.await.unwrap()
+        };
+
+        let (served, outcome) = tokio::join!(conn.serve(), respond);
+        served.unwrap();
+        assert_eq!(&b"a"[..], outcome.unwrap());
+
+        // The upstream is told that the connection is done
+        let mut rest = vec![];
+        upstream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(1, host.queue.len());
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Instant,
+};
+
+use futures::future::BoxFuture;
+use hickory_resolver::TokioResolver;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::config::{self, Dns};
+
+/// The addresses a name resolved to, and until when they can be used.
+pub struct Resolution {
+    pub addrs: Vec<IpAddr>,
+    pub valid_until: Instant,
+}
+
+/// Looks up the addresses of host names.
+pub trait Resolve: Send + Sync {
+    fn resolve<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Resolution>>;
+}
+
+/// Resolves names with the name servers of the system, and its hosts file.
+pub struct SystemResolver(TokioResolver);
+
+impl SystemResolver {
+    pub fn new() -> io::Result<Self> {
+        let resolver = TokioResolver::builder_tokio()
+            .and_then(|builder| builder.build())
+            .map_err(io::Error::other)?;
+
+        Ok(SystemResolver(resolver))
+    }
+}
+
+impl Resolve for SystemResolver {
+    fn resolve<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Resolution>> {
+        Box::pin(async move {
+            let lookup = self.0.lookup_ip(name).await.map_err(io::Error::other)?;
+            Ok(Resolution {
+                addrs: lookup.iter().collect(),
+                valid_until: lookup.valid_until(),
+            })
+        })
+    }
+}
+
+/// The name and port of `address` if its name has to be resolved. Not for IP
+/// addresses and unix sockets.
+pub fn name_and_port(address: &str) -> Option<(&str, u16)> {
+    if config::unix_socket(address).is_some() {
+        return None;
+    }
+
+    let (name, port) = address.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    if name.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+
+    Some((name, port))
+}
+
+/// Resolves `name` whenever its records expire, until `closed` is cancelled.
+///
+/// `connect` is called for every new address with a token that is cancelled
+/// once the name no longer resolves to it. The addresses from the last lookup
+/// are kept if a lookup fails or comes back empty.
+pub async fn watch(
+    name: &str,
+    port: u16,
+    config: &Dns,
+    resolver: &dyn Resolve,
+    closed: &CancellationToken,
+    mut connect: impl FnMut(SocketAddr, CancellationToken),
+) {
+    let mut current: HashMap<SocketAddr, CancellationToken> = HashMap::new();
+
+    loop {
+        let ttl = match resolver.resolve(name).await {
+            Ok(resolution) if !resolution.addrs.is_empty() => {
+                let resolved = resolution
+                    .addrs
+                    .iter()
+                    .map(|ip| SocketAddr::new(*ip, port))
+                    .collect::<Vec<_>>();
+
+                current.retain(|addr, removed| {
+                    let keep = resolved.contains(addr);
+                    if !keep {
+                        info!(name, %addr, "address was removed, draining its connections");
+                        removed.cancel();
+                    }
+                    keep
+                });
+
+                for addr in resolved {
+                    current.entry(addr).or_insert_with(|| {
+                        info!(name, %addr, "connecting to a new address");
+                        let token = closed.child_token();
+                        connect(addr, token.clone());
+                        token
+                    });
+                }
+
+                let ttl = resolution
+                    .valid_until
+                    .saturating_duration_since(Instant::now());
+                ttl.clamp(config.min_ttl, config.max_ttl.max(config.min_ttl))
+            }
+            Ok(_) => {
+                warn!(name, "name resolved to no addresses, keeping the old ones");
+                config.min_ttl
+            }
+            Err(e) => {
+                warn!(name, err = %e, "failed to resolve, keeping the old addresses");
+                config.min_ttl
+            }
+        };
+        debug!(name, ?ttl, "resolving again after the TTL");
+
+        tokio::select! {
+            _ = closed.cancelled() => return,
+            _ = tokio::time::sleep(ttl) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::VecDeque,
+        io,
+        net::{IpAddr, SocketAddr},
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use futures::future::BoxFuture;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{name_and_port, watch, Resolution, Resolve};
+    use crate::config::Dns;
+
+    /// Gives the answers in order and then keeps giving the last one. None is
+    /// a failed lookup.
+    struct Answers(Mutex<VecDeque<Option<Vec<IpAddr>>>>);
+
+    impl Resolve for Answers {
+        fn resolve<'a>(&'a self, _: &'a str) -> BoxFuture<'a, io::Result<Resolution>> {
+            let mut answers = self.0.lock().unwrap();
+            let answer = match answers.len() {
+                1 => answers[0].clone(),
+                _ => answers.pop_front().unwrap(),
+            };
+
+            Box::pin(async move {
+                Ok(Resolution {
+                    addrs: answer.ok_or_else(|| io::Error::other("SERVFAIL"))?,
+                    valid_until: Instant::now(),
+                })
+            })
+        }
+    }
+
+    fn ips(ips: &[&str]) -> Option<Vec<IpAddr>> {
+        Some(ips.iter().map(|ip| ip.parse().unwrap()).collect())
+    }
+
+    #[tokio::test]
+    async fn connections_follow_the_addresses_of_the_name() {
+        let resolver = Answers(Mutex::new(VecDeque::from([
+            ips(&["10.0.0.1", "10.0.0.2"]),
+            None,
+            ips(&[]),
+            ips(&["10.0.0.2", "10.0.0.3"]),
+        ])));
+        let config = Dns {
+            min_ttl: Duration::from_millis(5),
+            max_ttl: Duration::from_secs(60),
+            drain_timeout: Duration::from_secs(1),
+        };
+
+        let connected = Arc::new(Mutex::new(Vec::<(SocketAddr, CancellationToken)>::new()));
+        let closed = CancellationToken::new();
+        let watching = {
+            let connected = connected.clone();
+            let closed = closed.clone();
+            tokio::spawn(async move {
+                watch(
+                    "backend.internal",
+                    4444,
+                    &config,
+                    &resolver,
+                    &closed,
+                    |addr, token| connected.lock().unwrap().push((addr, token)),
+                )
+                .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        {
+            let connected = connected.lock().unwrap();
+            let addrs = connected
+                .iter()
+                .map(|(addr, token)| (addr.to_string(), token.is_cancelled()))
+                .collect::<Vec<_>>();
+            // Failed and empty lookups don't remove anything
+            assert_eq!(
+                vec![
+                    (String::from("10.0.0.1:4444"), true),
+                    (String::from("10.0.0.2:4444"), false),
+                    (String::from("10.0.0.3:4444"), false),
+                ],
+                addrs
+            );
+        }
+
+        closed.cancel();
+        watching.await.unwrap();
+        assert!(connected
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|(_, token)| token.is_cancelled()));
+    }
+
+    #[test]
+    fn only_names_are_resolved() {
+        assert_eq!(
+            Some(("backend.internal", 4444)),
+            name_and_port("backend.internal:4444")
+        );
+        assert_eq!(None, name_and_port("127.0.0.1:4444"));
+        assert_eq!(None, name_and_port("[::1]:4444"));
+        assert_eq!(None, name_and_port("unix:/run/backend.sock"));
+    }
+}
@@ -0,0 +1,274 @@
+use std::io;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::watch,
+    time::MissedTickBehavior,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::{
+    codec::{self, Codec, MAX_HEADER_LEN},
+    config::HealthCheck,
+    frame::{Frame, V1},
+    stream,
+    tls::Connector,
+};
+
+use super::host::Host;
+
+/// Whether a host should get requests. A host is available while it passes
+/// its health checks and isn't ejected by outlier detection. The connections
+/// of an unavailable host stop pulling from the queue until it recovers.
+pub struct Health {
+    status: watch::Sender<Status>,
+}
+
+#[derive(Clone, Copy)]
+struct Status {
+    healthy: bool,
+    ejected: bool,
+}
+
+impl Status {
+    fn available(self) -> bool {
+        self.healthy && !self.ejected
+    }
+}
+
+impl Health {
+    pub fn new(healthy: bool) -> Self {
+        Health {
+            status: watch::Sender::new(Status {
+                healthy,
+                ejected: false,
+            }),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.status.borrow().healthy
+    }
+
+    pub fn is_ejected(&self) -> bool {
+        self.status.borrow().ejected
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.status.borrow().available()
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.status.send_modify(|s| s.healthy = healthy);
+    }
+
+    pub fn set_ejected(&self, ejected: bool) {
+        self.status.send_modify(|s| s.ejected = ejected);
+    }
+
+    /// Resolves once the host is (or already is) `available`.
+    pub async fn wait_for(&self, available: bool) {
+        let mut rx = self.status.subscribe();
+        // The sender lives in self, so this can't fail
+        let _ = rx.wait_for(|s| s.available() == available).await;
+    }
+}
+
+/// Keeps track of consecutive probe results.
+struct Probes {
+    healthy: bool,
+    probed: bool,
+    successes: u32,
+    failures: u32,
+}
+
+impl Probes {
+    fn new(healthy: bool) -> Self {
+        Probes {
+            healthy,
+            probed: false,
+            successes: 0,
+            failures: 0,
+        }
+    }
+
+    /// Returns the new status if the result changed it. The first probe
+    /// decides the initial status regardless of the thresholds.
+    fn record(&mut self, success: bool, check: &HealthCheck) -> Option<bool> {
+        let first = !self.probed;
+        self.probed = true;
+
+        if success {
+            self.successes += 1;
+            self.failures = 0;
+        } else {
+            self.failures += 1;
+            self.successes = 0;
+        }
+
+        let healthy = match self.healthy {
+            false if success && (first || self.successes >= check.healthy_threshold) => true,
+            true if !success && (first || self.failures >= check.unhealthy_threshold) => false,
+            _ => return None,
+        };
+
+        self.healthy = healthy;
+        Some(healthy)
+    }
+}
+
+/// Probes `host` every `check.interval` until `closed` is cancelled.
+pub async fn run(
+    host: &'static Host,
+    codec: &'static dyn Codec,
+    check: &'static HealthCheck,
+    tls: Option<&'static Connector>,
+    closed: &CancellationToken,
+) {
+    let address = host.address;
+    let mut probes = Probes::new(host.health.is_healthy());
+
+    // The first tick completes immediately
+    let mut interval = tokio::time::interval(check.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = closed.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+
+        let success =
+            match tokio::time::timeout(check.timeout, probe(address, codec, check, tls)).await {
+                Ok(Ok(true)) => true,
+                Ok(Ok(false)) => {
+                    debug!(address, "health check got an unexpected response");
+                    false
+                }
+                Ok(Err(e)) => {
+                    debug!(address, err = ?e, "health check failed");
+                    false
+                }
+                Err(_) => {
+                    debug!(address, "health check timed out");
+                    false
+                }
+            };
+
+        match probes.record(success, check) {
+            Some(true) => info!(address, "upstream is healthy"),
+            Some(false) => warn!(address, "upstream is unhealthy"),
+            None => continue,
+        }
+
+        host.health.set_healthy(probes.healthy);
+    }
+}
+
+/// Sends the probe payload on a new connection and checks the response.
+async fn probe(
+    address: &str,
+    codec: &dyn Codec,
+    check: &HealthCheck,
+    tls: Option<&Connector>,
+) -> io::Result<bool> {
+    let mut stream = stream::connect(address, None, tls).await?;
+
+    let payload = &check.payload[..];
+    let mut buf = Vec::with_capacity(MAX_HEADER_LEN + payload.len());
+    let frame = match codec.multiplexed() {
+        true => Frame::with_request_id(0, payload.len() as u32),
+        false => Frame::new(V1, payload.len() as u32),
+    };
+    codec.encode(&frame, &mut buf);
+    buf.extend_from_slice(payload);
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+
+    let frame = codec::read_frame(codec, &mut stream)
+        .await?
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    // Only the prefix matters, the rest is dropped along with the connection
+    let expect = &check.expect[..];
+    if (frame.msg_len as usize) < expect.len() {
+        return Ok(false);
+    }
+
+    let mut response = vec![0; expect.len()];
+    stream.read_exact(&mut response).await?;
+
+    Ok(response == expect)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::{codec::Framing, config::HealthCheck, frame::Frame};
+
+    use super::{probe, Probes};
+
+    fn check(expect: &str) -> HealthCheck {
+        HealthCheck {
+            payload: b"PING".to_vec(),
+            expect: expect.as_bytes().to_vec(),
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(1),
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+
+    #[test]
+    fn thresholds_apply_after_the_first_probe() {
+        let check = check("PONG");
+        let mut probes = Probes::new(false);
+
+        assert_eq!(Some(true), probes.record(true, &check));
+
+        assert_eq!(None, probes.record(false, &check));
+        assert_eq!(None, probes.record(false, &check));
+        assert_eq!(None, probes.record(true, &check));
+        assert_eq!(None, probes.record(false, &check));
+        assert_eq!(None, probes.record(false, &check));
+        assert_eq!(Some(false), probes.record(false, &check));
+
+        assert_eq!(None, probes.record(true, &check));
+        assert_eq!(Some(true), probes.record(true, &check));
+    }
+
+    #[tokio::test]
+    async fn probe_matches_the_response_prefix() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?.to_string();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                // l3 forwards v1 payloads without a header
+                let mut payload = [0u8; 4];
+                stream.read_exact(&mut payload).await.unwrap();
+                assert_eq!(b"PING", &payload);
+
+                let response = b"PONG and more";
+                let frame = Frame::new(1, response.len() as u32);
+                stream.write_all(&frame.as_bytes()).await.unwrap();
+                stream.write_all(response).await.unwrap();
+            }
+        });
+
+        let codec = Framing::L3.codec();
+        assert!(probe(&address, codec, &check("PONG"), None).await?);
+        assert!(!probe(&address, codec, &check("PANG"), None).await?);
+        assert!(!probe(&address, codec, &check("PONG and more, and more"), None).await?);
+
+        Ok(())
+    }
+}
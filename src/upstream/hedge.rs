@@ -0,0 +1,128 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::config::Hedge;
+
+/// How many of the latest response times the hedge delay is based on.
+const SAMPLES: usize = 1000;
+/// The delay is recomputed every time this many responses were recorded.
+const REFRESH_EVERY: usize = 100;
+
+/// Decides how long a request waits for a response before it's hedged, and
+/// counts the hedges.
+pub struct Hedging {
+    config: &'static Hedge,
+    latencies: Mutex<Latencies>,
+    hedged: AtomicU64,
+    won: AtomicU64,
+}
+
+struct Latencies {
+    samples: Vec<Duration>,
+    next: usize,
+    delay: Option<Duration>,
+}
+
+/// What hedging did since the pool started.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Metrics {
+    /// Requests that were sent to a second host.
+    pub hedged: u64,
+    /// Hedged requests that the second host answered first.
+    pub won: u64,
+}
+
+impl Hedging {
+    pub fn new(config: &'static Hedge) -> Self {
+        Hedging {
+            config,
+            latencies: Mutex::new(Latencies {
+                samples: Vec::with_capacity(SAMPLES),
+                next: 0,
+                delay: None,
+            }),
+            hedged: AtomicU64::new(0),
+            won: AtomicU64::new(0),
+        }
+    }
+
+    /// How long to wait for a response before hedging. None until enough
+    /// responses were recorded to tell.
+    pub fn delay(&self) -> Option<Duration> {
+        let delay = self.latencies.lock().unwrap().delay?;
+        Some(delay.max(self.config.min_delay))
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let mut l = self.latencies.lock().unwrap();
+        let next = l.next;
+        match l.samples.get_mut(next) {
+            Some(sample) => *sample = latency,
+            None => l.samples.push(latency),
+        }
+        l.next = (next + 1) % SAMPLES;
+
+        if l.next.is_multiple_of(REFRESH_EVERY) {
+            l.delay = Some(percentile(&l.samples, self.config.percentile));
+        }
+    }
+
+    pub fn hedged(&self) {
+        self.hedged.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn won(&self) {
+        self.won.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            hedged: self.hedged.load(Ordering::Relaxed),
+            won: self.won.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn percentile(samples: &[Duration], percentile: u32) -> Duration {
+    let mut samples = samples.to_vec();
+    let i = (samples.len() * percentile as usize / 100).min(samples.len() - 1);
+    *samples.select_nth_unstable(i).1
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::config::Hedge;
+
+    use super::{Hedging, REFRESH_EVERY, SAMPLES};
+
+    #[test]
+    fn the_delay_follows_the_percentile_of_the_latest_responses() {
+        let config = Box::leak(Box::new(Hedge {
+            percentile: 90,
+            min_delay: Duration::from_millis(5),
+        }));
+        let hedging = Hedging::new(config);
+        let ms = Duration::from_millis;
+
+        for i in 0..REFRESH_EVERY - 1 {
+            hedging.record(ms(i as u64));
+        }
+        assert_eq!(None, hedging.delay());
+
+        hedging.record(ms(99));
+        assert_eq!(Some(ms(90)), hedging.delay());
+
+        // The old responses are forgotten
+        for _ in 0..SAMPLES {
+            hedging.record(ms(1));
+        }
+        assert_eq!(Some(ms(5)), hedging.delay());
+    }
+}
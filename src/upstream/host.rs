@@ -0,0 +1,104 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tracing::warn;
+
+use crate::config::{default_ewma_decay, Balancer, Upstream, UpstreamHost};
+
+use super::{
+    balancer::Latency, breaker::Breaker, health::Health, outlier::Stats, pool::RequestError,
+    queue::Queue,
+};
+
+/// An upstream host and the state that its connections share.
+pub struct Host {
+    pub address: &'static String,
+    pub weight: u32,
+    pub health: Health,
+    pub stats: Stats,
+    pub latency: Latency,
+    /// The requests picked for this host, waiting for one of its connections.
+    pub queue: Queue,
+    pub breaker: Option<Breaker>,
+    connected: AtomicUsize,
+    outstanding: AtomicUsize,
+}
+
+impl Host {
+    pub fn new(host: &'static UpstreamHost, config: &'static Upstream) -> Self {
+        // Hosts that are health checked don't get requests until they pass a check
+        let healthy = config.health_check.is_none();
+        let slow_response = config
+            .outlier_detection
+            .as_ref()
+            .and_then(|o| o.slow_response);
+
+        Host {
+            address: &host.addr,
+            weight: host.weight,
+            health: Health::new(healthy),
+            stats: Stats::new(slow_response),
+            latency: Latency::new(match config.balancer {
+                Balancer::PeakEwma { decay } => decay,
+                _ => default_ewma_decay(),
+            }),
+            queue: Queue::new(config),
+            breaker: config.circuit_breaker.as_ref().map(|c| {
+                Breaker::new(
+                    &host.addr,
+                    c.consecutive_failures,
+                    c.open_time,
+                    c.half_open_requests,
+                )
+            }),
+            connected: AtomicUsize::new(0),
+            outstanding: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether requests can be sent to the host right now.
+    pub fn accepts_requests(&self) -> bool {
+        self.health.is_available()
+            && self.connected.load(Ordering::Relaxed) > 0
+            && self.breaker.as_ref().is_none_or(Breaker::allows)
+    }
+
+    pub fn connected(&self) {
+        self.connected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called when a connection goes away. Once the last one is gone there
+    /// is nobody left to pick up the queued requests, so they fail.
+    pub fn disconnected(&self) {
+        if self.connected.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.fail_queued();
+        }
+    }
+
+    /// The requests that were assigned to the host and haven't completed yet,
+    /// whether they are queued or in flight.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    pub fn begin_request(&self) {
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn end_request(&self) {
+        self.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Fails the requests that are waiting for the host.
+    pub fn fail_queued(&self) {
+        let failed = self.queue.fail_all(|| RequestError::HostUnavailable {
+            address: self.address,
+        });
+
+        if failed > 0 {
+            warn!(
+                address = self.address,
+                failed, "failed the queued requests of an unavailable upstream"
+            );
+        }
+    }
+}
@@ -1,2 +1,11 @@
+pub mod balancer;
+pub mod breaker;
 pub mod connection;
+pub mod dns;
+pub mod health;
+pub mod hedge;
+pub mod host;
+pub mod outlier;
 pub mod pool;
+pub mod queue;
+pub mod retry;
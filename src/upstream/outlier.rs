@@ -0,0 +1,371 @@
+use std::{
+    cmp::min,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::config::OutlierDetection;
+
+use super::host::Host;
+
+/// The results of the requests sent to a host, recorded by its connections.
+pub struct Stats {
+    slow_response: Option<Duration>,
+    consecutive_failures: AtomicU32,
+    requests: AtomicU32,
+    failures: AtomicU32,
+}
+
+impl Stats {
+    pub fn new(slow_response: Option<Duration>) -> Self {
+        Stats {
+            slow_response,
+            consecutive_failures: AtomicU32::new(0),
+            requests: AtomicU32::new(0),
+            failures: AtomicU32::new(0),
+        }
+    }
+
+    /// Records a response that took `latency`. Slow responses count as failures.
+    pub fn record_response(&self, latency: Duration) {
+        match self.slow_response {
+            Some(slow) if latency > slow => self.record_failure(),
+            _ => {
+                self.requests.fetch_add(1, Ordering::Relaxed);
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn record_failure(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the consecutive failures, and the requests and failures since
+    /// the last call.
+    fn take(&self) -> (u32, u32, u32) {
+        (
+            self.consecutive_failures.load(Ordering::Relaxed),
+            self.requests.swap(0, Ordering::Relaxed),
+            self.failures.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+#[derive(Default)]
+struct Ejection {
+    /// Grows with every ejection and shrinks after every `base_ejection_time`
+    /// the host goes without failing, which is what makes repeat offenders
+    /// stay out longer.
+    count: u32,
+    until: Option<Instant>,
+    /// When the host last came back or `count` last shrank.
+    clean_since: Option<Instant>,
+}
+
+struct Detector {
+    config: &'static OutlierDetection,
+    ejections: Vec<Ejection>,
+}
+
+impl Detector {
+    fn new(config: &'static OutlierDetection, hosts: usize) -> Self {
+        Detector {
+            config,
+            ejections: (0..hosts).map(|_| Ejection::default()).collect(),
+        }
+    }
+
+    /// Brings back the hosts whose ejection is over and ejects the ones that
+    /// failed too many requests since the last evaluation.
+    fn evaluate(&mut self, hosts: &[Host], now: Instant) {
+        let config = self.config;
+        // Rounded down, and the last host is never ejected so there is always
+        // one left to take the requests
+        let max_ejected = min(
+            hosts.len() * config.max_ejection_percent as usize / 100,
+            hosts.len().saturating_sub(1),
+        );
+        let mut ejected = self
+            .ejections
+            .iter()
+            .filter(|e| e.until.is_some_and(|until| until > now))
+            .count();
+
+        for (host, ejection) in hosts.iter().zip(&mut self.ejections) {
+            match ejection.until {
+                Some(until) if until > now => continue,
+                Some(_) => {
+                    info!(address = host.address, "upstream is no longer ejected");
+                    ejection.until = None;
+                    ejection.clean_since = Some(now);
+                    // Whatever was recorded while it was ejected is stale
+                    host.stats.take();
+                    host.health.set_ejected(false);
+                    continue;
+                }
+                None => {}
+            }
+
+            let (consecutive_failures, requests, failures) = host.stats.take();
+            let outlier = (config.consecutive_failures > 0
+                && consecutive_failures >= config.consecutive_failures)
+                || (config.failure_percentage > 0
+                    && requests > 0
+                    && requests >= config.min_requests
+                    && failures * 100 >= requests * config.failure_percentage);
+
+            if !outlier {
+                match ejection.clean_since {
+                    Some(since)
+                        if now.saturating_duration_since(since) >= config.base_ejection_time =>
+                    {
+                        ejection.count = ejection.count.saturating_sub(1);
+                        ejection.clean_since = (ejection.count > 0).then_some(now);
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            // A failing interval starts the clean period over
+            if ejection.clean_since.is_some() {
+                ejection.clean_since = Some(now);
+            }
+
+            if ejected >= max_ejected {
+                warn!(
+                    address = host.address,
+                    consecutive_failures,
+                    requests,
+                    failures,
+                    "upstream is an outlier, but too many hosts are already ejected"
+                );
+                continue;
+            }
+
+            ejection.count += 1;
+            let duration = min(
+                config.base_ejection_time * ejection.count,
+                config.max_ejection_time,
+            );
+            ejection.until = Some(now + duration);
+            ejected += 1;
+
+            // Otherwise it would be ejected again as soon as it's back
+            host.stats.consecutive_failures.store(0, Ordering::Relaxed);
+            host.health.set_ejected(true);
+            warn!(
+                address = host.address,
+                consecutive_failures,
+                requests,
+                failures,
+                ?duration,
+                "ejecting upstream"
+            );
+        }
+    }
+}
+
+/// Evaluates `hosts` every `config.interval` until `closed` is cancelled.
+pub async fn run(
+    hosts: &'static [Host],
+    config: &'static OutlierDetection,
+    closed: &CancellationToken,
+) {
+    let mut detector = Detector::new(config, hosts.len());
+
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes immediately and there is nothing to evaluate yet
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = closed.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+
+        detector.evaluate(hosts, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::config::{OutlierDetection, Upstream, UpstreamHost};
+
+    use super::{Detector, Host};
+
+    fn hosts(n: usize) -> Vec<Host> {
+        let config: &'static Upstream = Box::leak(Box::new(Upstream {
+            outlier_detection: Some(config()),
+            ..Default::default()
+        }));
+
+        (0..n)
+            .map(|i| {
+                let host = Box::leak(Box::new(UpstreamHost::from(format!("host{i}"))));
+                Host::new(host, config)
+            })
+            .collect()
+    }
+
+    fn config() -> OutlierDetection {
+        OutlierDetection {
+            interval: Duration::from_secs(1),
+            consecutive_failures: 3,
+            failure_percentage: 50,
+            min_requests: 10,
+            slow_response: Some(Duration::from_millis(100)),
+            base_ejection_time: Duration::from_secs(10),
+            max_ejection_time: Duration::from_secs(25),
+            max_ejection_percent: 50,
+        }
+    }
+
+    #[test]
+    fn consecutive_failures_eject_with_a_growing_backoff() {
+        let hosts = hosts(2);
+        let mut detector = Detector::new(Box::leak(Box::new(config())), hosts.len());
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            hosts[0].stats.record_failure();
+        }
+        hosts[1].stats.record_response(Duration::from_millis(1));
+
+        detector.evaluate(&hosts, now);
+        assert!(hosts[0].health.is_ejected());
+        assert!(!hosts[1].health.is_ejected());
+
+        detector.evaluate(&hosts, now + Duration::from_secs(9));
+        assert!(hosts[0].health.is_ejected());
+
+        detector.evaluate(&hosts, now + Duration::from_secs(10));
+        assert!(!hosts[0].health.is_ejected());
+
+        // Slow responses count as failures
+        for _ in 0..3 {
+            hosts[0].stats.record_response(Duration::from_millis(200));
+        }
+
+        let now = now + Duration::from_secs(10);
+        detector.evaluate(&hosts, now);
+        detector.evaluate(&hosts, now + Duration::from_secs(19));
+        assert!(hosts[0].health.is_ejected());
+
+        detector.evaluate(&hosts, now + Duration::from_secs(20));
+        assert!(!hosts[0].health.is_ejected());
+    }
+
+    #[test]
+    fn failure_percentage_needs_enough_requests() {
+        let hosts = hosts(2);
+        let mut detector = Detector::new(Box::leak(Box::new(config())), hosts.len());
+        let now = Instant::now();
+
+        for _ in 0..4 {
+            hosts[0].stats.record_failure();
+            hosts[0].stats.record_response(Duration::from_millis(1));
+        }
+
+        detector.evaluate(&hosts, now);
+        assert!(!hosts[0].health.is_ejected());
+
+        for _ in 0..5 {
+            hosts[0].stats.record_failure();
+            hosts[0].stats.record_response(Duration::from_millis(1));
+        }
+
+        detector.evaluate(&hosts, now);
+        assert!(hosts[0].health.is_ejected());
+        assert!(!hosts[0].health.is_available());
+    }
+
+    #[test]
+    fn the_backoff_shrinks_after_a_clean_ejection_period() {
+        let hosts = hosts(2);
+        let mut detector = Detector::new(Box::leak(Box::new(config())), hosts.len());
+        let mut now = Instant::now();
+
+        for _ in 0..2 {
+            for _ in 0..3 {
+                hosts[0].stats.record_failure();
+            }
+            detector.evaluate(&hosts, now);
+            now += Duration::from_secs(20);
+            detector.evaluate(&hosts, now);
+            assert!(!hosts[0].health.is_ejected());
+        }
+        assert_eq!(2, detector.ejections[0].count);
+
+        // Clean intervals alone don't make up for the ejections
+        for _ in 0..9 {
+            now += Duration::from_secs(1);
+            detector.evaluate(&hosts, now);
+        }
+        assert_eq!(2, detector.ejections[0].count);
+
+        now += Duration::from_secs(1);
+        detector.evaluate(&hosts, now);
+        assert_eq!(1, detector.ejections[0].count);
+
+        now += Duration::from_secs(10);
+        detector.evaluate(&hosts, now);
+        assert_eq!(0, detector.ejections[0].count);
+    }
+
+    #[test]
+    fn ejections_are_capped() {
+        let hosts = hosts(4);
+        let mut detector = Detector::new(Box::leak(Box::new(config())), hosts.len());
+
+        for host in &hosts {
+            for _ in 0..3 {
+                host.stats.record_failure();
+            }
+        }
+
+        detector.evaluate(&hosts, Instant::now());
+        let ejected = hosts.iter().filter(|h| h.health.is_ejected()).count();
+        assert_eq!(2, ejected);
+    }
+
+    #[test]
+    fn the_last_host_is_never_ejected() {
+        let all = OutlierDetection {
+            max_ejection_percent: 100,
+            ..config()
+        };
+        let mut detector = Detector::new(Box::leak(Box::new(all)), 2);
+
+        let pair = hosts(2);
+        for host in &pair {
+            for _ in 0..3 {
+                host.stats.record_failure();
+            }
+        }
+
+        detector.evaluate(&pair, Instant::now());
+        let ejected = pair.iter().filter(|h| h.health.is_ejected()).count();
+        assert_eq!(1, ejected);
+
+        // Less than one host in 50% of them
+        let mut detector = Detector::new(Box::leak(Box::new(config())), 1);
+        let hosts = hosts(1);
+        for _ in 0..3 {
+            hosts[0].stats.record_failure();
+        }
+
+        detector.evaluate(&hosts, Instant::now());
+        assert!(!hosts[0].health.is_ejected());
+    }
+}
@@ -1,98 +1,331 @@
 use std::{
-    cmp::min,
     future::Future,
     io,
+    net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
 
-use crossbeam::sync::{Parker, Unparker};
-use tokio::sync::{oneshot, Mutex};
-use tracing::{error, info};
+use bytes::Bytes;
+use thiserror::Error;
+use tokio::sync::{oneshot, Notify};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, info, warn};
 
-use crate::config::Config;
+use crate::{
+    buffer::BufferPool,
+    config::{Config, Overflow},
+    frame::{Frame, FrameError},
+    tls::Connector,
+};
+
+use super::{
+    balancer::{self, Balancer, Random},
+    breaker::{Breaker, State},
+    connection::Connection,
+    dns::{self, Resolve, SystemResolver},
+    health,
+    hedge::{Hedging, Metrics},
+    host::Host,
+    outlier,
+    retry::{self, Budget},
+};
 
-use super::connection::Connection;
+/// Why a queued request didn't get a response.
+#[derive(Debug, Error)]
+pub enum RequestError {
+    #[error("request timed out in queue")]
+    QueueTimeout,
+    #[error("the request queue is full")]
+    QueueFull,
+    #[error("upstream {address} failed: {source}")]
+    Upstream {
+        address: &'static String,
+        source: io::Error,
+    },
+    #[error("upstream {address} failed before the request was sent: {source}")]
+    NotSent {
+        address: &'static String,
+        source: io::Error,
+    },
+    #[error("upstream {address} became unavailable")]
+    HostUnavailable { address: &'static String },
+    #[error("no upstream is available")]
+    NoUpstream,
+    #[error("the circuit breaker of the upstream cluster is open")]
+    CircuitOpen,
+    #[error("upstream {address} didn't respond within {timeout:?}")]
+    ResponseTimeout {
+        address: &'static String,
+        timeout: Duration,
+    },
+    #[error("upstream {address} sent an invalid frame: {source}")]
+    Frame {
+        address: &'static String,
+        source: FrameError,
+    },
+    #[error("the request queue is closed")]
+    QueueClosed,
+    #[error("connection was interrupted")]
+    Interrupted,
+}
+
+/// The response payload.
+pub type Outcome = Result<Bytes, RequestError>;
 
 pub struct Request {
-    pub(super) buff: Arc<Mutex<Vec<u8>>>,
-    pub(super) msg_len: usize,
-    pub(super) done: oneshot::Sender<i64>,
+    /// Shared by every attempt at sending the request.
+    pub(super) payload: Bytes,
+    pub(super) frame: Frame,
+    /// Longer responses are a protocol error.
+    pub(super) max_response_len: usize,
+    pub(super) done: oneshot::Sender<Outcome>,
     pub(super) queued_at: Instant,
 }
 
 pub trait AsyncRequestQueue {
     fn queue_request(
         &self,
-        buff: Arc<Mutex<Vec<u8>>>,
-        msg_len: usize,
-    ) -> impl Future<Output = Result<usize, io::Error>> + Send;
+        frame: Frame,
+        payload: Bytes,
+        max_response_len: usize,
+    ) -> impl Future<Output = Outcome> + Send;
+
+    /// Resolves once the queue can take another request.
+    fn ready(&self) -> impl Future<Output = ()> + Send;
 }
 
 pub struct Pool {
     config: &'static Config,
-    queue_tx: async_channel::Sender<Request>,
-    queue_rx: async_channel::Receiver<Request>,
+    buffers: &'static BufferPool,
+    /// Opens the connections to the hosts if the cluster uses TLS.
+    tls: Option<Connector>,
+    /// Looks up the addresses of the host names if they are re-resolved.
+    resolver: Option<Box<dyn Resolve>>,
+    hosts: Vec<Host>,
+    balancer: Box<dyn Balancer>,
+    retry_budget: Option<Budget>,
+    hedging: Option<Hedging>,
+    cluster_breaker: Option<Breaker>,
+    random: Random,
+    closed: CancellationToken,
+    connections: TaskTracker,
 }
 
 impl Pool {
-    pub fn new(config: &'static Config) -> Self {
-        let (tx, rx) = async_channel::unbounded::<Request>();
+    /// The host names are resolved with `resolver` if they are re-resolved, or
+    /// with the name servers of the system if it's not set.
+    pub fn new(
+        config: &'static Config,
+        buffers: &'static BufferPool,
+        resolver: Option<Box<dyn Resolve>>,
+    ) -> io::Result<Self> {
+        let hosts = config
+            .upstream
+            .hosts
+            .iter()
+            .map(|host| Host::new(host, &config.upstream))
+            .collect::<Vec<_>>();
 
-        Pool {
+        Ok(Pool {
             config,
-            queue_tx: tx,
-            queue_rx: rx,
+            buffers,
+            tls: config
+                .upstream
+                .tls
+                .as_ref()
+                .map(Connector::new)
+                .transpose()?,
+            resolver: match (&config.upstream.dns, resolver) {
+                (Some(_), None) => Some(Box::new(SystemResolver::new()?)),
+                (_, resolver) => resolver,
+            },
+            balancer: balancer::new(&config.upstream, &hosts),
+            hosts,
+            retry_budget: config.upstream.retry.as_ref().map(Budget::new),
+            hedging: config.upstream.hedge.as_ref().map(Hedging::new),
+            cluster_breaker: config.upstream.circuit_breaker.as_ref().and_then(|c| {
+                let threshold = c.cluster_consecutive_failures?;
+                Some(Breaker::new(
+                    "cluster",
+                    threshold,
+                    c.open_time,
+                    c.half_open_requests,
+                ))
+            }),
+            random: Random::new(),
+            closed: CancellationToken::new(),
+            connections: TaskTracker::new(),
+        })
+    }
+
+    /// Stops taking requests and waits for the upstream connections to close.
+    ///
+    /// Requests that are already queued are still sent to the upstreams, but
+    /// the responses of whatever is in flight once the queue is empty are
+    /// dropped. Drain the downstream connections first to avoid that.
+    pub async fn close(&self) {
+        info!("closing the upstream pool");
+        for host in &self.hosts {
+            host.queue.close();
+        }
+        self.closed.cancel();
+
+        self.connections.close();
+        self.connections.wait().await;
+        if let Some(metrics) = self.hedge_metrics() {
+            info!(
+                hedged = metrics.hedged,
+                won = metrics.won,
+                "hedged requests"
+            );
         }
+        info!("upstream pool closed");
     }
 
-    pub fn start(&'static self) {
+    /// The circuit state of every host, if circuit breakers are enabled.
+    pub fn circuits(&self) -> impl Iterator<Item = (&'static String, State)> + '_ {
+        self.hosts
+            .iter()
+            .filter_map(|h| Some((h.address, h.breaker.as_ref()?.state())))
+    }
+
+    /// The circuit state of the whole cluster, if its breaker is enabled.
+    pub fn cluster_circuit(&self) -> Option<State> {
+        self.cluster_breaker.as_ref().map(Breaker::state)
+    }
+
+    /// What hedging did so far, if it's enabled.
+    pub fn hedge_metrics(&self) -> Option<Metrics> {
+        self.hedging.as_ref().map(Hedging::metrics)
+    }
+
+    /// The number of requests waiting for an upstream connection.
+    pub fn queue_depth(&self) -> usize {
+        self.hosts.iter().map(|h| h.queue.len()).sum()
+    }
+
+    pub async fn start(&'static self) {
         info!("starting the upstream pool");
-        let parker = Parker::new();
+        let connected = Arc::new(Notify::new());
+        let codec = self.config.upstream.codec.codec();
 
-        for address in &self.config.upstream.hosts {
+        for (host, host_config) in self.hosts.iter().zip(&self.config.upstream.hosts) {
+            let connections = host_config
+                .connections
+                .unwrap_or(self.config.upstream.connections);
             info!(
-                address,
-                connections = self.config.upstream.connections,
+                address = host.address,
+                connections,
+                weight = host.weight,
                 "establishing connection(s)"
             );
 
-            for _ in 0..self.config.upstream.connections {
-                self.handle_connection(address, parker.unparker().clone());
+            let dns = self.config.upstream.dns.as_ref();
+            let resolving = dns.zip(self.resolver.as_deref());
+            match resolving.zip(dns::name_and_port(host.address)) {
+                // Every address of the name gets its own connections
+                Some(((dns, resolver), (name, port))) => {
+                    let connected = connected.clone();
+                    let connect = move |addr, closed: CancellationToken| {
+                        for _ in 0..connections {
+                            self.handle_connection(
+                                host,
+                                Some(addr),
+                                closed.clone(),
+                                connected.clone(),
+                            );
+                        }
+                    };
+                    self.connections.spawn(dns::watch(
+                        name,
+                        port,
+                        dns,
+                        resolver,
+                        &self.closed,
+                        connect,
+                    ));
+                }
+                None => {
+                    for _ in 0..connections {
+                        self.handle_connection(host, None, self.closed.clone(), connected.clone());
+                    }
+                }
+            }
+
+            if let Some(check) = &self.config.upstream.health_check {
+                self.connections.spawn(health::run(
+                    host,
+                    codec,
+                    check,
+                    self.tls.as_ref(),
+                    &self.closed,
+                ));
             }
         }
 
-        // Wait for at least one unpark call
-        // Could this cause a deadline if the machine only has a single thread?
-        parker.park();
+        if let Some(detection) = &self.config.upstream.outlier_detection {
+            self.connections
+                .spawn(outlier::run(&self.hosts, detection, &self.closed));
+        }
+
+        // Wait for at least one connection to be established
+        connected.notified().await;
     }
 
-    fn handle_connection(&'static self, address: &'static String, unparker: Unparker) {
+    /// Keeps a connection to the host open until `closed` is cancelled. It goes
+    /// to `addr` if the name of the host was resolved.
+    fn handle_connection(
+        &'static self,
+        host: &'static Host,
+        addr: Option<SocketAddr>,
+        closed: CancellationToken,
+        connected: Arc<Notify>,
+    ) {
+        let address = host.address;
         let mut try_num = 0;
 
-        tokio::spawn(async move {
+        self.connections.spawn(async move {
             loop {
-                let rx = self.queue_rx.clone();
-                // TODO: These error branches need to handle graceful shutdowns
-                //  if we are shutting down the application and something is waiting in the Err branch
-                //  it should cancel.
-                match Connection::connect(address, self.config.service.max_msg_len, rx).await {
+                let config = &self.config.upstream;
+                let connect = Connection::connect(
+                    host,
+                    addr,
+                    config,
+                    self.tls.as_ref(),
+                    self.buffers,
+                    closed.clone(),
+                );
+                let conn = tokio::select! {
+                    biased;
+                    _ = closed.cancelled() => return,
+                    conn = connect => conn,
+                };
+
+                match conn {
                     Err(e) => {
                         try_num += 1;
-                        let sleep_duration = Duration::from_secs(min(60, try_num));
-                        error!(try_num, address, err = ?e, ?sleep_duration, "failed to connect to upstream");
-                        tokio::time::sleep(sleep_duration).await;
-                        continue;
+                        let sleep_duration = reconnect_backoff(try_num, self.random.next());
+                        error!(try_num, address, ?addr, err = ?e, ?sleep_duration, "failed to connect to upstream");
+                        tokio::select! {
+                            _ = closed.cancelled() => return,
+                            _ = tokio::time::sleep(sleep_duration) => continue,
+                        }
                     }
-                    Ok(ref mut c) => {
-                        unparker.unpark();
+                    Ok(mut c) => {
+                        connected.notify_one();
                         // reset the try num since the connection was successful
                         try_num = 0;
-                        match c.serve().await {
+                        host.connected();
+                        let served = c.serve().await;
+                        host.disconnected();
+
+                        match served {
                             Ok(_) => {
                                 // Nothing to do here. The connection was
                                 // terminated as planned and we are not going to
                                 // reconnect
+                                debug!(address, "upstream connection closed");
                                 return;
                             }
                             Err(e) => {
@@ -107,33 +340,627 @@ impl Pool {
     }
 }
 
-impl AsyncRequestQueue for Pool {
-    async fn queue_request(
+impl Pool {
+    /// Sends the request, and retries it on other hosts if retries are enabled.
+    async fn send_with_retries(
         &self,
-        buf: Arc<Mutex<Vec<u8>>>,
-        msg_len: usize,
-    ) -> Result<usize, io::Error> {
-        let (tx, rx) = oneshot::channel::<i64>();
+        frame: &Frame,
+        payload: &Bytes,
+        max_response_len: usize,
+    ) -> Outcome {
+        let key = self.balancer.key(frame, payload);
+        let retry = self.config.upstream.retry.as_ref();
+        let Some((retry, budget)) = retry.zip(self.retry_budget.as_ref()) else {
+            return self
+                .send(frame, key, payload, max_response_len, &mut vec![])
+                .await;
+        };
+        budget.record_request();
+
+        let idempotent = frame.is_idempotent();
+        let mut tried = Vec::new();
+        let mut failed: Option<RequestError> = None;
+        loop {
+            let sent = self
+                .send(frame, key, payload, max_response_len, &mut tried)
+                .await;
+            let err = match (sent, failed) {
+                (Ok(response), _) => return Ok(response),
+                // There is no other host left to retry on
+                (Err(RequestError::NoUpstream), Some(err)) => return Err(err),
+                (Err(err), _) => err,
+            };
+
+            if tried.len() > retry.attempts as usize || !retry::retryable(&err, idempotent) {
+                return Err(err);
+            }
+
+            if !budget.try_retry() {
+                warn!(err = %err, "the retry budget is exhausted");
+                return Err(err);
+            }
+
+            warn!(attempt = tried.len(), err = %err, "retrying the request on another host");
+            failed = Some(err);
+        }
+    }
+
+    /// Sends the request to a host that it wasn't `tried` on yet.
+    ///
+    /// Idempotent requests are hedged: if hedging is enabled and the host
+    /// takes too long to respond, the request is sent to a second host as
+    /// well. The first response wins and the other attempt is dropped.
+    async fn send(
+        &self,
+        frame: &Frame,
+        key: Option<u64>,
+        payload: &Bytes,
+        max_response_len: usize,
+        tried: &mut Vec<usize>,
+    ) -> Outcome {
+        let Some(first) = self.pick(key, tried) else {
+            warn!("no upstream is available");
+            return Err(RequestError::NoUpstream);
+        };
+        let first_attempt = self.send_to(first, frame, payload, max_response_len);
+
+        let hedging = self.hedging.as_ref().filter(|_| frame.is_idempotent());
+        let Some((hedging, delay)) = hedging.and_then(|h| Some((h, h.delay()?))) else {
+            return first_attempt.await;
+        };
+
+        tokio::pin!(first_attempt);
+        tokio::select! {
+            outcome = &mut first_attempt => return outcome,
+            _ = tokio::time::sleep(delay) => {}
+        }
+
+        let Some(second) = self.pick(key, tried) else {
+            return first_attempt.await;
+        };
+        debug!(
+            address = self.hosts[second].address,
+            ?delay,
+            "no response yet, hedging the request"
+        );
+        hedging.hedged();
+        let second_attempt = self.send_to(second, frame, payload, max_response_len);
+        tokio::pin!(second_attempt);
+
+        // A failed attempt still leaves the other one a chance to succeed
+        tokio::select! {
+            outcome = &mut first_attempt => match outcome {
+                Ok(response) => Ok(response),
+                Err(_) => second_attempt.await,
+            },
+            outcome = &mut second_attempt => match outcome {
+                Ok(response) => {
+                    hedging.won();
+                    Ok(response)
+                }
+                Err(_) => first_attempt.await,
+            },
+        }
+    }
+
+    /// Picks a host that the request wasn't `tried` on and adds it to them.
+    fn pick(&self, key: Option<u64>, tried: &mut Vec<usize>) -> Option<usize> {
+        let picked = self.balancer.pick(&self.hosts, tried, key)?;
+        tried.push(picked);
+        Some(picked)
+    }
+
+    async fn send_to(
+        &self,
+        host: usize,
+        frame: &Frame,
+        payload: &Bytes,
+        max_response_len: usize,
+    ) -> Outcome {
+        let host = &self.hosts[host];
+        let (tx, rx) = oneshot::channel::<Outcome>();
+        let queued_at = Instant::now();
         let req = Request {
-            buff: buf,
-            msg_len,
+            payload: payload.clone(),
+            frame: frame.clone(),
+            max_response_len,
             done: tx,
-            queued_at: Instant::now(),
+            queued_at,
+        };
+
+        let permit = match host.breaker.as_ref().map(Breaker::try_acquire) {
+            // The circuit opened after the host was picked
+            Some(None) => {
+                return Err(RequestError::HostUnavailable {
+                    address: host.address,
+                })
+            }
+            Some(permit) => permit,
+            None => None,
         };
 
-        if let Err(e) = self.queue_tx.send(req).await {
-            let err_msg = "attempt to write to closed queue channel";
-            error!(err=?e, err_msg);
-            return Err(io::Error::new(io::ErrorKind::Other, err_msg));
+        // The attempt is dropped if a hedge wins
+        let _outstanding = Outstanding::begin(host);
+        let outcome = match host.queue.push(req).await {
+            Err(e) => Err(e),
+            Ok(()) => {
+                let _latency = self.hedging.as_ref().map(|h| Latency::begin(h, queued_at));
+                rx.await.unwrap_or(Err(RequestError::Interrupted))
+            }
+        };
+
+        if let Some(permit) = permit {
+            permit.record(&outcome);
         }
 
-        match rx.await {
-            Err(_e) => Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "connection was interrupted",
-            )),
-            Ok(n) if n < 0 => Err(io::Error::new(io::ErrorKind::Other, "upstream error")),
-            Ok(n) => Ok(usize::try_from(n).unwrap()),
+        outcome
+    }
+}
+
+/// Counts a request against the outstanding ones of a host for as long as it lives.
+struct Outstanding<'a>(&'a Host);
+
+impl<'a> Outstanding<'a> {
+    fn begin(host: &'a Host) -> Self {
+        host.begin_request();
+        Outstanding(host)
+    }
+}
+
+impl Drop for Outstanding<'_> {
+    fn drop(&mut self) {
+        self.0.end_request();
+    }
+}
+
+/// Records how long an attempt took once it ends, whether it got a response,
+/// failed, or was dropped because the other attempt won. Only recording the
+/// winners would make the hedge delay look shorter than the responses are.
+struct Latency<'a>(&'a Hedging, Instant);
+
+impl<'a> Latency<'a> {
+    fn begin(hedging: &'a Hedging, queued_at: Instant) -> Self {
+        Latency(hedging, queued_at)
+    }
+}
+
+impl Drop for Latency<'_> {
+    fn drop(&mut self) {
+        self.0.record(self.1.elapsed());
+    }
+}
+
+impl AsyncRequestQueue for Pool {
+    async fn queue_request(
+        &self,
+        frame: Frame,
+        payload: Bytes,
+        max_response_len: usize,
+    ) -> Outcome {
+        let Some(breaker) = &self.cluster_breaker else {
+            return self
+                .send_with_retries(&frame, &payload, max_response_len)
+                .await;
+        };
+
+        let Some(permit) = breaker.try_acquire() else {
+            debug!("the circuit of the upstream cluster is open");
+            return Err(RequestError::CircuitOpen);
+        };
+
+        let outcome = self
+            .send_with_retries(&frame, &payload, max_response_len)
+            .await;
+        permit.record(&outcome);
+
+        outcome
+    }
+
+    async fn ready(&self) {
+        let upstream = &self.config.upstream;
+        if upstream.queue_capacity.is_none() || upstream.queue_overflow != Overflow::Wait {
+            return;
+        }
+
+        let ready: Vec<_> = self
+            .hosts
+            .iter()
+            .filter(|h| h.accepts_requests())
+            .map(|h| Box::pin(h.queue.ready()))
+            .collect();
+
+        // Requests fail right away when no host is available, so there is nothing to wait for
+        if !ready.is_empty() {
+            futures::future::select_all(ready).await;
+        }
+    }
+}
+
+/// The first reconnect waits for about this long, and every failed one
+/// doubles that up to `RECONNECT_MAX`.
+const RECONNECT_BASE: Duration = Duration::from_millis(100);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+/// Exponential backoff with jitter, so that the connections that failed
+/// together don't all reconnect at the same time.
+fn reconnect_backoff(try_num: u32, random: u64) -> Duration {
+    let backoff = RECONNECT_BASE
+        .saturating_mul(1 << try_num.saturating_sub(1).min(16))
+        .min(RECONNECT_MAX);
+
+    // Somewhere between half of it and all of it
+    backoff / 2 + backoff.mul_f64((random % 1000) as f64 / 2000.0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        net::IpAddr,
+        sync::{
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    use bytes::Bytes;
+    use futures::future::BoxFuture;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{reconnect_backoff, AsyncRequestQueue, Pool, RequestError, RECONNECT_MAX};
+    use crate::{
+        buffer::BufferPool,
+        codec::Framing,
+        config::{Config, Dns, Hedge, Retry, Service, Upstream, UpstreamHost},
+        frame::{Frame, FrameError, FLAG_IDEMPOTENT, V1},
+        upstream::dns::{Resolution, Resolve},
+    };
+
+    /// Resolves every name to whatever the test set last.
+    #[derive(Clone)]
+    struct Switch(Arc<Mutex<Vec<IpAddr>>>);
+
+    impl Switch {
+        fn set(&self, ip: &str) {
+            *self.0.lock().unwrap() = vec![ip.parse().unwrap()];
+        }
+    }
+
+    impl Resolve for Switch {
+        fn resolve<'a>(&'a self, _: &'a str) -> BoxFuture<'a, io::Result<Resolution>> {
+            let addrs = self.0.lock().unwrap().clone();
+            Box::pin(async move {
+                Ok(Resolution {
+                    addrs,
+                    valid_until: Instant::now(),
+                })
+            })
+        }
+    }
+
+    async fn accept(listener: &TcpListener) -> TcpStream {
+        let accepted = tokio::time::timeout(Duration::from_secs(5), listener.accept());
+        accepted.await.expect("no connection").unwrap().0
+    }
+
+    /// Answers a u16 length prefixed request with `response`.
+    async fn respond(upstream: &mut TcpStream, request: &[u8], response: &[u8]) {
+        let mut buf = vec![0u8; request.len() + 2];
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(request, &buf[2..]);
+
+        let mut frame = (response.len() as u16).to_be_bytes().to_vec();
+        frame.extend_from_slice(response);
+        upstream.write_all(&frame).await.unwrap();
+    }
+
+    /// A u16 length prefixed upstream that answers every request with
+    /// `response`, after `delay` milliseconds.
+    struct Backend {
+        addr: String,
+        requests: Arc<AtomicUsize>,
+        delay: Arc<AtomicU64>,
+    }
+
+    impl Backend {
+        async fn start(response: &'static [u8]) -> Backend {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend = Backend {
+                addr: listener.local_addr().unwrap().to_string(),
+                requests: Arc::new(AtomicUsize::new(0)),
+                delay: Arc::new(AtomicU64::new(0)),
+            };
+
+            let (requests, delay) = (backend.requests.clone(), backend.delay.clone());
+            tokio::spawn(async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let (requests, delay) = (requests.clone(), delay.clone());
+                    tokio::spawn(async move {
+                        let mut len = [0u8; 2];
+                        while stream.read_exact(&mut len).await.is_ok() {
+                            let mut request = vec![0u8; u16::from_be_bytes(len) as usize];
+                            stream.read_exact(&mut request).await.unwrap();
+                            requests.fetch_add(1, Ordering::Relaxed);
+
+                            let delay = Duration::from_millis(delay.load(Ordering::Relaxed));
+                            tokio::time::sleep(delay).await;
+                            let mut frame = (response.len() as u16).to_be_bytes().to_vec();
+                            frame.extend_from_slice(response);
+                            if stream.write_all(&frame).await.is_err() {
+                                return;
+                            }
+                        }
+                    });
+                }
+            });
+
+            backend
+        }
+
+        /// Responds with more than `MAX_RESPONSE_LEN`, which only fails the request.
+        async fn failing() -> Backend {
+            Backend::start(&[b'x'; 32]).await
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::Relaxed)
+        }
+    }
+
+    const MAX_RESPONSE_LEN: usize = 16;
+
+    /// Starts a pool over `backends` and waits for all of them to be connected.
+    async fn pool(backends: &[&Backend], upstream: Upstream) -> &'static Pool {
+        let config: &'static Config = Box::leak(Box::new(Config {
+            service: Service {
+                host: String::from("localhost"),
+                port: None,
+                max_msg_len: MAX_RESPONSE_LEN,
+                codec: Framing::L3V2,
+                max_in_flight: 1,
+                tls: None,
+                socket_mode: None,
+            },
+            upstream: Upstream {
+                hosts: backends
+                    .iter()
+                    .map(|b| UpstreamHost::from(b.addr.clone()))
+                    .collect(),
+                codec: Framing::U16Be,
+                queue_timeout: Duration::from_secs(5),
+                ..upstream
+            },
+            listeners: vec![],
+            shutdown: Default::default(),
+        }));
+        let buffers = Box::leak(Box::new(BufferPool::new()));
+        let pool: &'static Pool = Box::leak(Box::new(Pool::new(config, buffers, None).unwrap()));
+
+        tokio::spawn(pool.start());
+        while !pool.hosts.iter().all(|h| h.accepts_requests()) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        pool
+    }
+
+    async fn send_idempotent(pool: &Pool, payload: &'static [u8]) -> super::Outcome {
+        let mut frame = Frame::with_request_id(1, payload.len() as u32);
+        frame.set_flags(FLAG_IDEMPOTENT);
+        let payload = Bytes::from_static(payload);
+        pool.queue_request(frame, payload, MAX_RESPONSE_LEN).await
+    }
+
+    fn retry(attempts: u32, budget_percent: u32, min_retries: u32) -> Option<Retry> {
+        Some(Retry {
+            attempts,
+            budget_percent,
+            min_retries,
+            budget_window: Duration::from_secs(10),
+        })
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_retried_on_another_host() {
+        let failing = Backend::failing().await;
+        let healthy = Backend::start(b"ok").await;
+        let pool = pool(
+            &[&failing, &healthy],
+            Upstream {
+                retry: retry(1, 100, 10),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        // Every other request is sent to the failing host first
+        for _ in 0..4 {
+            assert_eq!(&b"ok"[..], send_idempotent(pool, b"req").await.unwrap());
         }
+        assert!(failing.requests() > 0);
+        assert_eq!(4, healthy.requests());
+
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn retries_go_to_hosts_that_werent_tried_up_to_the_attempts() {
+        let backends = [
+            Backend::failing().await,
+            Backend::failing().await,
+            Backend::failing().await,
+        ];
+        let pool = pool(
+            &backends.iter().collect::<Vec<_>>(),
+            Upstream {
+                retry: retry(1, 100, 10),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        match send_idempotent(pool, b"req").await {
+            Err(RequestError::Frame {
+                source: FrameError::MessageTooLarge(32),
+                ..
+            }) => {}
+            o => panic!("invalid outcome {o:?}"),
+        }
+        let requests: Vec<_> = backends.iter().map(Backend::requests).collect();
+        assert_eq!(2, requests.iter().sum::<usize>());
+        assert!(requests.iter().all(|&n| n <= 1));
+
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn an_exhausted_budget_stops_retries() {
+        let backends = [Backend::failing().await, Backend::failing().await];
+        let pool = pool(
+            &backends.iter().collect::<Vec<_>>(),
+            Upstream {
+                retry: retry(1, 0, 1),
+                ..Default::default()
+            },
+        )
+        .await;
+        let requests = || backends.iter().map(Backend::requests).sum::<usize>();
+
+        assert!(send_idempotent(pool, b"req").await.is_err());
+        assert_eq!(2, requests());
+
+        // The only retry of the window is used up
+        assert!(send_idempotent(pool, b"req").await.is_err());
+        assert_eq!(3, requests());
+
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn slow_requests_are_hedged_to_another_host() {
+        let slow = Backend::start(b"slow").await;
+        let fast = Backend::start(b"fast").await;
+        let pool = pool(
+            &[&slow, &fast],
+            Upstream {
+                hedge: Some(Hedge {
+                    percentile: 50,
+                    min_delay: Duration::from_millis(1),
+                }),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        // Nothing is hedged until there are enough latencies to go by
+        let hedging = pool.hedging.as_ref().unwrap();
+        assert_eq!(None, hedging.delay());
+        for _ in 0..100 {
+            hedging.record(Duration::from_millis(100));
+        }
+        let delay = hedging.delay().unwrap();
+        assert_eq!(Duration::from_millis(100), delay);
+
+        slow.delay.store(500, Ordering::Relaxed);
+        for _ in 0..2 {
+            let sent_to_slow = slow.requests();
+            let started = Instant::now();
+            assert_eq!(&b"fast"[..], send_idempotent(pool, b"req").await.unwrap());
+
+            // The slow host is hedged once the delay is up, and the fast one wins
+            let elapsed = started.elapsed();
+            assert!(elapsed < Duration::from_millis(400));
+            if slow.requests() > sent_to_slow {
+                assert!(elapsed >= delay);
+            }
+        }
+
+        let metrics = pool.hedge_metrics().unwrap();
+        assert!(metrics.hedged > 0);
+        assert_eq!(metrics.hedged, metrics.won);
+
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn connections_follow_the_addresses_of_the_host_names() {
+        let old = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = old.local_addr().unwrap().port();
+        let new = TcpListener::bind(("127.0.0.2", port)).await.unwrap();
+
+        let config: &'static Config = Box::leak(Box::new(Config {
+            service: Service {
+                host: String::from("localhost"),
+                port: None,
+                max_msg_len: 16,
+                codec: Framing::L3,
+                max_in_flight: 1,
+                tls: None,
+                socket_mode: None,
+            },
+            upstream: Upstream {
+                hosts: vec![UpstreamHost::from(format!("backend.test:{port}"))],
+                codec: Framing::U16Be,
+                queue_timeout: Duration::from_secs(5),
+                dns: Some(Dns {
+                    min_ttl: Duration::from_millis(10),
+                    max_ttl: Duration::from_millis(10),
+                    drain_timeout: Duration::from_secs(5),
+                }),
+                ..Default::default()
+            },
+            listeners: vec![],
+            shutdown: Default::default(),
+        }));
+        let resolver = Switch(Arc::new(Mutex::new(vec![])));
+        resolver.set("127.0.0.1");
+        let buffers = Box::leak(Box::new(BufferPool::new()));
+        let pool: &'static Pool = Box::leak(Box::new(
+            Pool::new(config, buffers, Some(Box::new(resolver.clone()))).unwrap(),
+        ));
+
+        tokio::spawn(pool.start());
+        let mut old_conn = accept(&old).await;
+
+        let send = |payload: &'static [u8]| {
+            let frame = Frame::new(V1, payload.len() as u32);
+            tokio::spawn(pool.queue_request(frame, Bytes::from_static(payload), 16))
+        };
+
+        // The request is in flight when the name moves to the new address
+        let first = send(b"first");
+        let mut sent = [0u8; 7];
+        old_conn.read_exact(&mut sent).await.unwrap();
+        resolver.set("127.0.0.2");
+        let mut new_conn = accept(&new).await;
+
+        // The old connection is drained before it's closed
+        old_conn.write_all(b"\0\x03one").await.unwrap();
+        assert_eq!(&b"one"[..], first.await.unwrap().unwrap());
+        assert_eq!(0, old_conn.read(&mut [0u8; 1]).await.unwrap());
+
+        let second = send(b"second");
+        respond(&mut new_conn, b"second", b"two").await;
+        assert_eq!(&b"two"[..], second.await.unwrap().unwrap());
+
+        pool.close().await;
+    }
+
+    #[test]
+    fn reconnects_back_off_exponentially_up_to_the_max() {
+        let ms = Duration::from_millis;
+
+        assert_eq!(ms(50), reconnect_backoff(1, 0));
+        assert_eq!(ms(100), reconnect_backoff(2, 0));
+        assert!(reconnect_backoff(2, 999) < ms(200));
+        assert!(reconnect_backoff(3, 999) > ms(399));
+
+        assert_eq!(RECONNECT_MAX / 2, reconnect_backoff(100, 0));
+        assert!(reconnect_backoff(100, 999) <= RECONNECT_MAX);
     }
 }
@@ -0,0 +1,373 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{Semaphore, TryAcquireError};
+use tracing::{debug, warn};
+
+use crate::config::{Codel, Overflow, Upstream};
+
+use super::pool::{Request, RequestError};
+
+/// The requests waiting for an upstream connection to pick them up.
+///
+/// Requests that wait for too long are failed with a queue timeout instead of
+/// being handed out, since whoever sent them has likely given up already.
+///
+/// A bounded queue keeps a permit for every free slot. Pushing takes one and
+/// popping gives it back.
+pub struct Queue {
+    tx: async_channel::Sender<Request>,
+    rx: async_channel::Receiver<Request>,
+    slots: Option<Semaphore>,
+    overflow: Overflow,
+    timeout: Duration,
+    codel: Option<Mutex<CodelState>>,
+}
+
+impl Queue {
+    pub fn new(config: &'static Upstream) -> Self {
+        let (tx, rx) = async_channel::unbounded::<Request>();
+
+        Queue {
+            tx,
+            rx,
+            slots: config.queue_capacity.map(Semaphore::new),
+            overflow: config.queue_overflow,
+            timeout: config.queue_timeout,
+            codel: config
+                .codel
+                .as_ref()
+                .map(|c| Mutex::new(CodelState::new(c))),
+        }
+    }
+
+    pub async fn push(&self, req: Request) -> Result<(), RequestError> {
+        if let Some(slots) = &self.slots {
+            self.take_slot(slots).await?;
+        }
+
+        self.tx.send(req).await.map_err(|e| {
+            warn!(err = ?e, "attempt to write to closed queue channel");
+            RequestError::QueueClosed
+        })
+    }
+
+    async fn take_slot(&self, slots: &Semaphore) -> Result<(), RequestError> {
+        let permit = match (self.overflow, slots.try_acquire()) {
+            (_, Ok(permit)) => permit,
+            (_, Err(TryAcquireError::Closed)) => return Err(RequestError::QueueClosed),
+            (Overflow::Reject, Err(TryAcquireError::NoPermits)) => {
+                warn!(
+                    depth = self.len(),
+                    "the request queue is full, rejecting the request"
+                );
+                return Err(RequestError::QueueFull);
+            }
+            (Overflow::DropOldest, Err(TryAcquireError::NoPermits)) => {
+                // The slot of the oldest request is handed over to the new one
+                if let Ok(oldest) = self.rx.try_recv() {
+                    warn!(
+                        depth = self.len(),
+                        "the request queue is full, dropping the oldest request"
+                    );
+                    let _ = oldest.done.send(Err(RequestError::QueueFull)); // Nothing to do if the channel is closed
+                    return Ok(());
+                }
+
+                // The queue was emptied in the meantime, which frees up slots
+                slots
+                    .acquire()
+                    .await
+                    .map_err(|_| RequestError::QueueClosed)?
+            }
+            (Overflow::Wait, Err(TryAcquireError::NoPermits)) => {
+                debug!(
+                    depth = self.len(),
+                    "the request queue is full, waiting for room"
+                );
+                slots
+                    .acquire()
+                    .await
+                    .map_err(|_| RequestError::QueueClosed)?
+            }
+        };
+
+        permit.forget();
+        Ok(())
+    }
+
+    /// Resolves once there is room in the queue, or right away if requests
+    /// that don't fit don't have to wait for one.
+    pub async fn ready(&self) {
+        if let (Some(slots), Overflow::Wait) = (&self.slots, self.overflow) {
+            // Only checking, the slot is taken when the request is pushed
+            let _ = slots.acquire().await;
+        }
+    }
+
+    /// Fails every request in the queue with `err`. Returns how many there were.
+    pub fn fail_all(&self, err: impl Fn() -> RequestError) -> usize {
+        let mut failed = 0;
+        while let Ok(req) = self.rx.try_recv() {
+            if let Some(slots) = &self.slots {
+                slots.add_permits(1);
+            }
+
+            let _ = req.done.send(Err(err())); // Nothing to do if the channel is closed
+            failed += 1;
+        }
+
+        failed
+    }
+
+    /// The number of requests in the queue.
+    pub fn len(&self) -> usize {
+        self.rx.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rx.is_empty()
+    }
+
+    /// Waits for the next request that hasn't timed out. Returns None once
+    /// the queue is closed and empty.
+    ///
+    /// Dropping the future before it completes doesn't lose any requests.
+    pub async fn pop(&self) -> Option<Request> {
+        loop {
+            let req = self.rx.recv().await.ok()?;
+            if let Some(slots) = &self.slots {
+                slots.add_permits(1);
+            }
+
+            // Whoever queued the request gave up on it, like a hedge that lost
+            if req.done.is_closed() {
+                continue;
+            }
+
+            let delay = req.queued_at.elapsed();
+            let timeout = match &self.codel {
+                Some(codel) => codel.lock().unwrap().timeout(delay, self.timeout),
+                None => self.timeout,
+            };
+
+            if delay > timeout {
+                warn!(?delay, ?timeout, "request timed out in queue");
+                let _ = req.done.send(Err(RequestError::QueueTimeout)); // Nothing to do if the channel is closed
+                continue;
+            }
+
+            debug!(
+                ?delay,
+                depth = self.len(),
+                "picked up a request from the queue"
+            );
+            return Some(req);
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Stops taking requests. The ones that are already queued can still be popped.
+    pub fn close(&self) {
+        self.tx.close();
+        if let Some(slots) = &self.slots {
+            slots.close();
+        }
+    }
+}
+
+/// Tracks whether the queue is standing, which is when even the request that
+/// waited the least in an interval waited longer than the target.
+///
+/// A standing queue doesn't go away on its own, so while there is one the
+/// requests are shed as soon as they wait longer than the target instead of
+/// the full queue timeout. Short bursts are let through.
+struct CodelState {
+    config: &'static Codel,
+    interval_end: Option<Instant>,
+    min_delay: Duration,
+    overloaded: bool,
+}
+
+impl CodelState {
+    fn new(config: &'static Codel) -> Self {
+        CodelState {
+            config,
+            interval_end: None,
+            min_delay: Duration::MAX,
+            overloaded: false,
+        }
+    }
+
+    /// Records the delay of a request and returns how long it's allowed to wait.
+    fn timeout(&mut self, delay: Duration, max: Duration) -> Duration {
+        self.record(delay, Instant::now());
+
+        match self.overloaded {
+            true => self.config.target,
+            false => max,
+        }
+    }
+
+    fn record(&mut self, delay: Duration, now: Instant) {
+        self.min_delay = self.min_delay.min(delay);
+
+        match self.interval_end {
+            Some(end) if now < end => {}
+            Some(end) => {
+                // Nothing was queued for a whole interval if it ended a while ago
+                let overloaded =
+                    self.min_delay > self.config.target && now < end + self.config.interval;
+                if overloaded != self.overloaded {
+                    warn!(overloaded, min_delay = ?self.min_delay, "the request queue changed state");
+                }
+
+                self.overloaded = overloaded;
+                self.min_delay = delay;
+                self.interval_end = Some(now + self.config.interval);
+            }
+            None => self.interval_end = Some(now + self.config.interval),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+    use tokio::sync::oneshot;
+
+    use crate::{
+        config::{Codel, Overflow, Upstream},
+        frame::{Frame, V1},
+        upstream::pool::{Outcome, Request, RequestError},
+    };
+
+    use super::{CodelState, Queue};
+
+    fn queue(capacity: usize, overflow: Overflow) -> Queue {
+        Queue::new(Box::leak(Box::new(Upstream {
+            queue_timeout: Duration::from_secs(1),
+            queue_capacity: Some(capacity),
+            queue_overflow: overflow,
+            ..Default::default()
+        })))
+    }
+
+    fn request(msg_len: u32) -> (Request, oneshot::Receiver<Outcome>) {
+        let (done, outcome) = oneshot::channel();
+        let req = Request {
+            payload: Bytes::from(vec![0; msg_len as usize]),
+            frame: Frame::new(V1, msg_len),
+            max_response_len: msg_len as usize,
+            done,
+            queued_at: Instant::now(),
+        };
+
+        (req, outcome)
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_rejects_new_requests() {
+        let queue = queue(1, Overflow::Reject);
+        let (first, _first) = request(1);
+        queue.push(first).await.unwrap();
+
+        match queue.push(request(2).0).await {
+            Err(RequestError::QueueFull) => {}
+            r => panic!("invalid result {r:?}"),
+        }
+
+        assert_eq!(1, queue.pop().await.unwrap().frame.msg_len);
+        queue.push(request(3).0).await.unwrap();
+        assert_eq!(1, queue.len());
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_drops_the_oldest_request() {
+        let queue = queue(1, Overflow::DropOldest);
+        let (first, outcome) = request(1);
+        queue.push(first).await.unwrap();
+        let (second, _second) = request(2);
+        queue.push(second).await.unwrap();
+
+        match outcome.await.unwrap() {
+            Err(RequestError::QueueFull) => {}
+            o => panic!("invalid outcome {o:?}"),
+        }
+        assert_eq!(2, queue.pop().await.unwrap().frame.msg_len);
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_makes_new_requests_wait() {
+        let queue = queue(1, Overflow::Wait);
+        let (first, _first) = request(1);
+        queue.push(first).await.unwrap();
+
+        let wait = Duration::from_millis(10);
+        assert!(tokio::time::timeout(wait, queue.ready()).await.is_err());
+        assert!(tokio::time::timeout(wait, queue.push(request(2).0))
+            .await
+            .is_err());
+
+        queue.pop().await.unwrap();
+        queue.ready().await;
+        let (third, _third) = request(3);
+        queue.push(third).await.unwrap();
+        assert_eq!(3, queue.pop().await.unwrap().frame.msg_len);
+    }
+
+    #[tokio::test]
+    async fn requests_that_were_given_up_on_are_skipped() {
+        let queue = queue(2, Overflow::Reject);
+        queue.push(request(1).0).await.unwrap();
+        let (second, _second) = request(2);
+        queue.push(second).await.unwrap();
+
+        assert_eq!(2, queue.pop().await.unwrap().frame.msg_len);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn codel_sheds_at_the_target_while_the_queue_is_standing() {
+        let config = Box::leak(Box::new(Codel {
+            target: Duration::from_millis(5),
+            interval: Duration::from_millis(100),
+        }));
+        let mut codel = CodelState::new(config);
+        let max = Duration::from_millis(50);
+        let ms = Duration::from_millis;
+        let now = Instant::now();
+
+        // A burst that drains within the interval is fine
+        codel.record(ms(20), now);
+        codel.record(ms(1), now + ms(50));
+        codel.record(ms(20), now + ms(100));
+        assert!(!codel.overloaded);
+
+        // Every request in the interval waited longer than the target
+        codel.record(ms(10), now + ms(150));
+        codel.record(ms(30), now + ms(200));
+        assert!(codel.overloaded);
+        assert_eq!(ms(5), codel.timeout(ms(30), max));
+
+        // The queue drained
+        codel.record(ms(1), now + ms(250));
+        codel.record(ms(1), now + ms(300));
+        assert!(!codel.overloaded);
+
+        // Nothing was queued for a while
+        codel.record(ms(10), now + ms(400));
+        codel.record(ms(10), now + ms(500));
+        assert!(codel.overloaded);
+        codel.record(ms(10), now + ms(800));
+        assert!(!codel.overloaded);
+    }
+}
@@ -0,0 +1,156 @@
+use std::{sync::Mutex, time::Instant};
+
+use crate::config::Retry;
+
+use super::pool::RequestError;
+
+/// Whether a request that failed with `err` can be sent again.
+///
+/// Requests that never made it to an upstream are always safe to retry. The
+/// ones that did might have been acted on, so only idempotent ones are.
+pub fn retryable(err: &RequestError, idempotent: bool) -> bool {
+    match err {
+        RequestError::NotSent { .. } | RequestError::HostUnavailable { .. } => true,
+        RequestError::Upstream { .. }
+        | RequestError::ResponseTimeout { .. }
+        | RequestError::Frame { .. } => idempotent,
+        // The request already waited for as long as it could, or retrying
+        // wouldn't change anything
+        RequestError::QueueTimeout
+        | RequestError::QueueFull
+        | RequestError::NoUpstream
+        | RequestError::CircuitOpen
+        | RequestError::QueueClosed
+        | RequestError::Interrupted => false,
+    }
+}
+
+/// Limits retries to a percentage of the requests.
+///
+/// Requests and retries are counted in fixed windows. The counts of the
+/// previous window are carried over so that the budget doesn't reset all at
+/// once at the start of every window.
+pub struct Budget {
+    config: &'static Retry,
+    windows: Mutex<Windows>,
+}
+
+struct Windows {
+    started_at: Instant,
+    requests: u64,
+    retries: u64,
+    previous_requests: u64,
+    previous_retries: u64,
+}
+
+impl Budget {
+    pub fn new(config: &'static Retry) -> Self {
+        Budget {
+            config,
+            windows: Mutex::new(Windows {
+                started_at: Instant::now(),
+                requests: 0,
+                retries: 0,
+                previous_requests: 0,
+                previous_retries: 0,
+            }),
+        }
+    }
+
+    pub fn record_request(&self) {
+        self.record(Instant::now(), |w| w.requests += 1);
+    }
+
+    /// Takes a retry out of the budget. Returns false if there is none left.
+    pub fn try_retry(&self) -> bool {
+        self.try_retry_at(Instant::now())
+    }
+
+    fn try_retry_at(&self, now: Instant) -> bool {
+        let config = self.config;
+        self.record(now, |w| {
+            let requests = w.requests + w.previous_requests;
+            let retries = w.retries + w.previous_retries;
+            let allowed =
+                (requests * config.budget_percent as u64 / 100).max(config.min_retries as u64);
+
+            if retries >= allowed {
+                return false;
+            }
+
+            w.retries += 1;
+            true
+        })
+    }
+
+    fn record<T>(&self, now: Instant, f: impl FnOnce(&mut Windows) -> T) -> T {
+        let mut w = self.windows.lock().unwrap();
+        let elapsed = now.saturating_duration_since(w.started_at);
+        if elapsed >= self.config.budget_window {
+            // Nothing carries over if a whole window went by without anything in it
+            let carry = elapsed < self.config.budget_window * 2;
+            w.previous_requests = if carry { w.requests } else { 0 };
+            w.previous_retries = if carry { w.retries } else { 0 };
+            w.requests = 0;
+            w.retries = 0;
+            w.started_at = now;
+        }
+
+        f(&mut w)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::{config::Retry, upstream::pool::RequestError};
+
+    use super::{retryable, Budget};
+
+    #[test]
+    fn only_idempotent_requests_are_retried_once_sent() {
+        let address: &'static String = Box::leak(Box::new(String::from("localhost:4444")));
+        let not_sent = RequestError::HostUnavailable { address };
+        let timed_out = RequestError::ResponseTimeout {
+            address,
+            timeout: Duration::from_secs(1),
+        };
+
+        assert!(retryable(&not_sent, false));
+        assert!(!retryable(&timed_out, false));
+        assert!(retryable(&timed_out, true));
+        assert!(!retryable(&RequestError::QueueTimeout, true));
+    }
+
+    #[test]
+    fn retries_are_limited_to_a_percentage_of_the_requests() {
+        let config = Box::leak(Box::new(Retry {
+            attempts: 1,
+            budget_percent: 10,
+            min_retries: 2,
+            budget_window: Duration::from_secs(10),
+        }));
+        let budget = Budget::new(config);
+        let now = Instant::now();
+
+        // The minimum is there even without any traffic
+        assert!(budget.try_retry_at(now));
+        assert!(budget.try_retry_at(now));
+        assert!(!budget.try_retry_at(now));
+
+        for _ in 0..40 {
+            budget.record_request();
+        }
+        assert!(budget.try_retry_at(now));
+        assert!(budget.try_retry_at(now));
+        assert!(!budget.try_retry_at(now));
+
+        // The previous window still counts
+        let now = now + Duration::from_secs(10);
+        assert!(!budget.try_retry_at(now));
+
+        let now = now + Duration::from_secs(10);
+        assert!(budget.try_retry_at(now));
+    }
+}
@@ -1,10 +1,10 @@
-use std::io;
+use std::{collections::HashMap, io};
 
-use l3::frame::Frame;
+use l3::frame::{ErrorCode, Frame, HEADER_LEN};
 use rand::{distributions::DistString, Rng};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    net::{TcpStream, UnixStream},
 };
 use tracing::info;
 
@@ -23,9 +23,23 @@ impl Client<TcpStream> {
 
         Ok(client)
     }
+}
+
+impl Client<UnixStream> {
+    pub async fn connect_unix(client_id: usize, path: &str) -> io::Result<Self> {
+        let stream = UnixStream::connect(path).await?;
+        let client = Client { stream, client_id };
 
+        Ok(client)
+    }
+}
+
+impl<T> Client<T>
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
     pub async fn send_request(&mut self, req_id: usize, print_result: bool) -> io::Result<()> {
-        let (mut stream_reader, mut stream_writer) = self.stream.split();
+        let (mut stream_reader, mut stream_writer) = tokio::io::split(&mut self.stream);
 
         let char_len = rand::thread_rng().gen_range(3..4);
         let mut msg: String =
@@ -57,4 +71,108 @@ impl Client<TcpStream> {
         assert_eq!(expected, reversed);
         Ok(())
     }
+
+    /// Sends `n_req` v1 requests without waiting for the responses, which
+    /// have to come back in the same order.
+    pub async fn send_pipelined_requests(&mut self, n_req: usize) -> io::Result<()> {
+        let (mut stream_reader, mut stream_writer) = tokio::io::split(&mut self.stream);
+
+        let mut sent = vec![];
+        for _ in 0..n_req {
+            let char_len = rand::thread_rng().gen_range(3..10);
+            let mut msg: String =
+                rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), char_len);
+            msg.push('\n');
+
+            let frame = Frame::new(1, msg.len().try_into().unwrap());
+            let payload = [&frame.as_bytes(), msg.as_bytes()].concat();
+
+            stream_writer.write_all(&payload).await?;
+            sent.push(msg);
+        }
+
+        for (i, msg) in sent.iter().enumerate() {
+            let mut buf: Vec<u8> = vec![0; msg.len()];
+            stream_reader.read_exact(&mut buf).await?;
+
+            let expected = msg.chars().rev().collect::<String>();
+            assert_eq!(
+                expected,
+                std::str::from_utf8(&buf).unwrap(),
+                "client {} req {}",
+                self.client_id,
+                i
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Sends `n_req` v2 requests without waiting for the responses and then
+    /// matches the responses to the requests by their id.
+    pub async fn send_multiplexed_requests(&mut self, n_req: u16) -> io::Result<()> {
+        let (mut stream_reader, mut stream_writer) = tokio::io::split(&mut self.stream);
+
+        let mut sent = HashMap::new();
+        for id in 0..n_req {
+            let char_len = rand::thread_rng().gen_range(3..10);
+            let msg: String =
+                rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), char_len);
+
+            let frame = Frame::with_request_id(id, msg.len().try_into().unwrap());
+            let payload = [&frame.as_bytes(), msg.as_bytes()].concat();
+
+            stream_writer.write_all(&payload).await?;
+            sent.insert(id, payload);
+        }
+
+        let mut header = [0u8; HEADER_LEN];
+        while !sent.is_empty() {
+            stream_reader.read_exact(&mut header).await?;
+            let frame = Frame::from_bytes(&header).expect("invalid response frame");
+            let id = frame.request_id().expect("expected a v2 response");
+
+            let mut buf: Vec<u8> = vec![0; frame.msg_len as usize];
+            stream_reader.read_exact(&mut buf).await?;
+
+            assert!(
+                !frame.is_error(),
+                "client {} req {} failed with error code {:?}",
+                self.client_id,
+                id,
+                buf
+            );
+
+            let payload = sent.remove(&id).expect("unexpected request id");
+            let expected = payload[HEADER_LEN..]
+                .iter()
+                .rev()
+                .copied()
+                .collect::<Vec<_>>();
+            assert_eq!(expected, buf, "client {} req {}", self.client_id, id);
+        }
+
+        Ok(())
+    }
+
+    /// Sends a v2 request with a payload of `len` bytes and expects an error frame back.
+    pub async fn send_failing_request(&mut self, id: u16, len: usize) -> io::Result<()> {
+        let (mut stream_reader, mut stream_writer) = tokio::io::split(&mut self.stream);
+
+        let frame = Frame::with_request_id(id, len.try_into().unwrap());
+        let payload = [&frame.as_bytes()[..], &vec![b'a'; len]].concat();
+        stream_writer.write_all(&payload).await?;
+
+        let mut header = [0u8; HEADER_LEN];
+        stream_reader.read_exact(&mut header).await?;
+        let frame = Frame::from_bytes(&header).expect("invalid response frame");
+        assert!(frame.is_error());
+        assert_eq!(Some(id), frame.request_id());
+
+        let mut code = [0u8; 1];
+        stream_reader.read_exact(&mut code).await?;
+        assert_eq!(ErrorCode::MessageTooLarge as u8, code[0]);
+
+        Ok(())
+    }
 }
@@ -1,8 +1,8 @@
 use std::io::{self};
 
-use l3::frame::Frame;
+use l3::frame::{Frame, HEADER_LEN};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     net::{TcpListener, TcpStream},
 };
 use tracing::{debug, error, info};
@@ -10,16 +10,29 @@ use tracing::{debug, error, info};
 pub struct Server {
     pub port: u16,
     listener: TcpListener,
+    /// Whether the load balancer talks `l3_v2` to it. Requests are bare lines
+    /// otherwise.
+    v2: bool,
 }
 
 impl Server {
     pub async fn listen() -> io::Result<Self> {
+        Self::bind(false).await
+    }
+
+    /// Expects v2 headers on every request, as the `l3_v2` codec sends them.
+    pub async fn listen_v2() -> io::Result<Self> {
+        Self::bind(true).await
+    }
+
+    async fn bind(v2: bool) -> io::Result<Self> {
         let listener = TcpListener::bind("127.0.0.1:0").await?;
         let addr = listener.local_addr()?;
 
         Ok(Server {
             port: addr.port(),
             listener,
+            v2,
         })
     }
 
@@ -31,11 +44,30 @@ impl Server {
                 .await
                 .expect("upstream accept failure");
 
+            let v2 = self.v2;
             tokio::spawn(async move {
                 // TODO: Don't think that this will break the test...
-                handle_connection(stream)
-                    .await
-                    .expect("handle connection failure");
+                let handled = match v2 {
+                    true => handle_v2_connection(stream).await,
+                    false => handle_connection(stream).await,
+                };
+                handled.expect("handle connection failure");
+            });
+        }
+    }
+
+    /// Accepts connections and reads from them, but never responds.
+    pub async fn serve_hung(&mut self) -> io::Result<()> {
+        loop {
+            let (mut stream, _) = self.listener.accept().await?;
+
+            tokio::spawn(async move {
+                let mut buf = [0u8; 128];
+                while let Ok(n) = stream.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                }
             });
         }
     }
@@ -46,6 +78,7 @@ async fn handle_connection(mut stream: TcpStream) -> io::Result<()> {
     let mut reader = BufReader::with_capacity(128, stream_reader);
     let mut buf = String::with_capacity(128);
     loop {
+        // v1 requests are forwarded without a header
         match reader.read_line(&mut buf).await {
             Ok(0) => {
                 info!("connection closed");
@@ -69,3 +102,29 @@ async fn handle_connection(mut stream: TcpStream) -> io::Result<()> {
 
     Ok(())
 }
+
+async fn handle_v2_connection(mut stream: TcpStream) -> io::Result<()> {
+    let (mut reader, mut writer) = stream.split();
+    let mut header = [0u8; HEADER_LEN];
+    loop {
+        match reader.read_exact(&mut header).await {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                info!("connection closed");
+                return Ok(());
+            }
+            read => read?,
+        };
+        let frame = Frame::from_bytes(&header).expect("invalid v2 frame");
+        let id = frame.request_id().expect("l3_v2 requests are v2 frames");
+
+        let mut msg = vec![0u8; frame.msg_len as usize];
+        reader.read_exact(&mut msg).await?;
+        msg.reverse();
+
+        let frame = Frame::with_request_id(id, msg.len() as u32);
+        let payload = [&frame.as_bytes(), msg.as_slice()].concat();
+        debug!(id, reversed = ?msg);
+
+        writer.write_all(&payload).await?;
+    }
+}
@@ -1,11 +1,17 @@
 use futures::future::join_all;
 use std::{io, time::Duration};
+use tokio::{io::AsyncReadExt, net::TcpStream, task::JoinHandle};
 use tracing::{debug, Level};
 
 use dummy_upstream::Server;
 use l3::{
-    config::{Config, Service, Upstream},
-    daemon::Daemon,
+    codec::Framing,
+    config::{
+        CircuitBreaker, Codel, Config, HealthCheck, OutlierDetection, Service, Shutdown, Upstream,
+        UpstreamHost,
+    },
+    daemon::{Daemon, ShutdownHandle},
+    upstream::{breaker::State, pool::Pool},
 };
 
 use crate::dummy_downstream::Client;
@@ -14,6 +20,14 @@ mod dummy_downstream;
 mod dummy_upstream;
 
 const LB_PORT: u16 = 8000;
+const LB_V6_PORT: u16 = 8001;
+
+fn lb_socket() -> String {
+    std::env::temp_dir()
+        .join(format!("l3-integration-{}.sock", std::process::id()))
+        .to_string_lossy()
+        .into_owned()
+}
 
 #[tokio::test(flavor = "multi_thread")]
 async fn test_the_world() -> io::Result<()> {
@@ -23,77 +37,196 @@ async fn test_the_world() -> io::Result<()> {
     let upstream_ports = start_the_upstream().await?;
     tokio::time::sleep(Duration::from_secs(1)).await;
 
-    start_the_lb(&upstream_ports).await?;
+    let (shutdown, daemon, pool) = start_the_lb(&upstream_ports).await?;
     tokio::time::sleep(Duration::from_secs(1)).await;
 
     run_downstream().await?;
+    check_the_pool(pool);
+
+    stop_the_lb(shutdown, daemon).await?;
 
     Ok(())
 }
 
-async fn start_the_upstream() -> io::Result<[u16; 4]> {
-    let mut s1 = Server::listen().await?;
+async fn start_the_upstream() -> io::Result<[u16; 5]> {
+    let mut s1 = Server::listen_v2().await?;
     let s1_port = s1.port;
 
     tokio::spawn(async move {
         s1.serve().await.expect("serve failure");
     });
 
-    let mut s2 = Server::listen().await?;
+    let mut s2 = Server::listen_v2().await?;
     let s2_port = s2.port;
 
     tokio::spawn(async move {
         s2.serve().await.expect("serve failure");
     });
 
-    let mut s3 = Server::listen().await?;
+    let mut s3 = Server::listen_v2().await?;
     let s3_port = s3.port;
 
     tokio::spawn(async move {
         s3.serve().await.expect("serve failure");
     });
 
-    let mut s4 = Server::listen().await?;
+    let mut s4 = Server::listen_v2().await?;
     let s4_port = s4.port;
 
     tokio::spawn(async move {
         s4.serve().await.expect("serve failure");
     });
 
-    Ok([s1_port, s2_port, s3_port, s4_port])
+    // Takes connections but never responds, health checks keep it out of the pool
+    let mut hung = Server::listen().await?;
+    let hung_port = hung.port;
+
+    tokio::spawn(async move {
+        hung.serve_hung().await.expect("serve failure");
+    });
+
+    Ok([s1_port, s2_port, s3_port, s4_port, hung_port])
 }
 
-async fn start_the_lb(upstream_ports: &[u16]) -> io::Result<()> {
-    let hosts: Vec<String> = upstream_ports
+async fn start_the_lb(
+    upstream_ports: &[u16],
+) -> io::Result<(ShutdownHandle, JoinHandle<()>, &'static Pool)> {
+    // The first upstream gets twice as many requests as the others
+    let hosts: Vec<UpstreamHost> = upstream_ports
         .iter()
-        .map(|p| format!("localhost:{}", p))
+        .enumerate()
+        .map(|(i, p)| UpstreamHost {
+            addr: format!("localhost:{}", p),
+            weight: if i == 0 { 2 } else { 1 },
+            connections: None,
+        })
         .collect();
 
     let conf = Config {
         service: Service {
             host: String::from("localhost"),
-            port: LB_PORT,
+            port: Some(LB_PORT),
             max_msg_len: 100,
+            codec: Framing::L3,
+            max_in_flight: 16,
+            tls: None,
+            socket_mode: None,
         },
         upstream: Upstream {
             hosts,
             connections: 25,
+            codec: Framing::L3V2,
+            pipeline_depth: 4,
+            queue_timeout: Duration::from_millis(100),
+            codel: Some(Codel {
+                target: Duration::from_millis(20),
+                interval: Duration::from_millis(100),
+            }),
+            circuit_breaker: Some(CircuitBreaker {
+                consecutive_failures: 5,
+                open_time: Duration::from_secs(1),
+                half_open_requests: 1,
+                cluster_consecutive_failures: Some(50),
+            }),
+            response_timeout: Some(Duration::from_secs(1)),
+            health_check: Some(HealthCheck {
+                payload: b"PING\n".to_vec(),
+                expect: b"\nGNIP".to_vec(),
+                interval: Duration::from_millis(200),
+                timeout: Duration::from_millis(100),
+                healthy_threshold: 2,
+                unhealthy_threshold: 2,
+            }),
+            outlier_detection: Some(OutlierDetection {
+                interval: Duration::from_secs(1),
+                consecutive_failures: 5,
+                failure_percentage: 0,
+                min_requests: 0,
+                slow_response: None,
+                base_ejection_time: Duration::from_secs(10),
+                max_ejection_time: Duration::from_secs(60),
+                max_ejection_percent: 20,
+            }),
+            ..Default::default()
+        },
+        listeners: vec![
+            Service {
+                host: String::from("::1"),
+                port: Some(LB_V6_PORT),
+                max_msg_len: 10,
+                codec: Framing::L3,
+                max_in_flight: 1,
+                tls: None,
+                socket_mode: None,
+            },
+            Service {
+                host: format!("unix:{}", lb_socket()),
+                port: None,
+                max_msg_len: 100,
+                codec: Framing::L3,
+                max_in_flight: 16,
+                tls: None,
+                socket_mode: Some(0o600),
+            },
+        ],
+        shutdown: Shutdown {
+            drain_timeout: Duration::from_secs(1),
         },
     };
 
     let c = Box::leak(Box::new(conf));
-    let daemon = Daemon::new(c);
+    let daemon = Daemon::new(c).expect("daemon creation failure");
+    let shutdown = daemon.shutdown_handle();
+    let pool = daemon.upstream_pool();
 
-    tokio::spawn(async move {
+    let handle = tokio::spawn(async move {
         daemon.run().await.expect("daemon run failure");
     });
 
+    Ok((shutdown, handle, pool))
+}
+
+fn check_the_pool(pool: &Pool) {
+    // Every host has a breaker, health checks kept the hung one from failing requests
+    let circuits: Vec<_> = pool.circuits().collect();
+    assert_eq!(5, circuits.len());
+    assert!(circuits.iter().all(|(_, state)| *state == State::Closed));
+    assert_eq!(Some(State::Closed), pool.cluster_circuit());
+
+    // All the clients got their responses, nothing is left waiting
+    assert_eq!(0, pool.queue_depth());
+
+    // Hedging isn't enabled
+    assert_eq!(None, pool.hedge_metrics());
+}
+
+async fn stop_the_lb(shutdown: ShutdownHandle, daemon: JoinHandle<()>) -> io::Result<()> {
+    let mut idle = TcpStream::connect(format!("localhost:{}", LB_PORT)).await?;
+    // Give the server a chance to accept it
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    shutdown.shutdown();
+
+    tokio::time::timeout(Duration::from_secs(5), daemon)
+        .await
+        .expect("the daemon should stop within the drain timeout")
+        .expect("daemon run failure");
+
+    // Idle clients are disconnected and no new connections are accepted
+    assert_eq!(0, idle.read(&mut [0u8; 1]).await?);
+    assert!(TcpStream::connect(format!("localhost:{}", LB_PORT))
+        .await
+        .is_err());
+    // The socket file of the unix socket listener is cleaned up
+    assert!(std::fs::metadata(lb_socket()).is_err());
+
     Ok(())
 }
 
 async fn run_downstream() -> io::Result<()> {
     const N_CLIENTS: usize = 5;
     const N_REQ: usize = 50;
+    const N_MULTIPLEXED_CLIENTS: usize = 5;
+    const N_MULTIPLEXED_REQ: u16 = 50;
 
     let mut handlers = vec![];
     for i in 0..N_CLIENTS {
@@ -111,6 +244,70 @@ async fn run_downstream() -> io::Result<()> {
         handlers.push(handler);
     }
 
+    // v1 clients can pipeline their requests
+    let handler = tokio::spawn(async move {
+        let mut c = Client::connect(
+            N_CLIENTS + N_MULTIPLEXED_CLIENTS + 1,
+            format!("localhost:{}", LB_PORT),
+        )
+        .await
+        .expect("should be able to connect to the load balancer");
+        c.send_pipelined_requests(N_REQ)
+            .await
+            .expect("send_pipelined_requests should not return an error");
+    });
+    handlers.push(handler);
+
+    // v2 clients share the load balancer with the v1 clients
+    for i in 0..N_MULTIPLEXED_CLIENTS {
+        let handler = tokio::spawn(async move {
+            let mut c = Client::connect(N_CLIENTS + i, format!("localhost:{}", LB_PORT))
+                .await
+                .expect("should be able to connect to the load balancer");
+            c.send_multiplexed_requests(N_MULTIPLEXED_REQ)
+                .await
+                .expect("send_multiplexed_requests should not return an error");
+
+            // Errors are reported in a frame and the connection stays usable
+            c.send_failing_request(7, 200)
+                .await
+                .expect("send_failing_request should not return an error");
+            c.send_multiplexed_requests(N_MULTIPLEXED_REQ)
+                .await
+                .expect("send_multiplexed_requests should not return an error");
+        });
+
+        handlers.push(handler);
+    }
+
+    // A second listener shares the upstream pool but has a smaller max_msg_len
+    let handler = tokio::spawn(async move {
+        let mut c = Client::connect(
+            N_CLIENTS + N_MULTIPLEXED_CLIENTS,
+            format!("[::1]:{}", LB_V6_PORT),
+        )
+        .await
+        .expect("should be able to connect to the load balancer");
+        c.send_multiplexed_requests(N_MULTIPLEXED_REQ)
+            .await
+            .expect("send_multiplexed_requests should not return an error");
+        c.send_failing_request(7, 20)
+            .await
+            .expect("send_failing_request should not return an error");
+    });
+    handlers.push(handler);
+
+    // Listeners can be on unix sockets too
+    let handler = tokio::spawn(async move {
+        let mut c = Client::connect_unix(N_CLIENTS + N_MULTIPLEXED_CLIENTS + 2, &lb_socket())
+            .await
+            .expect("should be able to connect to the load balancer");
+        c.send_multiplexed_requests(N_MULTIPLEXED_REQ)
+            .await
+            .expect("send_multiplexed_requests should not return an error");
+    });
+    handlers.push(handler);
+
     for h in join_all(handlers).await {
         if let Err(e) = h {
             panic!("{}", e);